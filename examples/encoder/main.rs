@@ -29,6 +29,7 @@ fn test_data() {
             &PngEncodingOptions {
                 compression: CompressionLevel::Fast,
                 write_timestamp: false,
+                ..Default::default()
             },
         );
 
@@ -50,6 +51,7 @@ fn main() {
         &PngEncodingOptions {
             compression: CompressionLevel::None,
             write_timestamp: true,
+            ..Default::default()
         },
     );
 
@@ -58,6 +60,7 @@ fn main() {
         &PngEncodingOptions {
             compression: CompressionLevel::Fast,
             write_timestamp: true,
+            ..Default::default()
         },
     );
 
@@ -0,0 +1,232 @@
+use std::time::Duration;
+
+use crate::{ByteOrder, Image, ImageType};
+
+///How the frame area is treated once the frame has been displayed
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum DisposeOp {
+    ///The canvas is left as is
+    #[default]
+    None,
+    ///The frame area is cleared to fully transparent black
+    Background,
+    ///The frame area is reverted to what it was before the frame was rendered
+    Previous,
+}
+
+///How a frame is combined with the canvas
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum BlendOp {
+    ///The frame replaces the contents of the frame area
+    #[default]
+    Source,
+    ///The frame is alpha composited over the contents of the frame area
+    Over,
+}
+
+///Animation information stored in the acTL chunk
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AnimationControl {
+    ///Number of frames in the animation
+    pub num_frames: u32,
+    ///Number of times the animation is played, 0 means forever
+    pub num_plays: u32,
+}
+
+///Placement and timing of a single frame, stored in the fcTL chunk
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FrameControl {
+    ///Width of the frame
+    pub width: u32,
+    ///Height of the frame
+    pub height: u32,
+    ///Horizontal position of the frame on the canvas
+    pub x_offset: u32,
+    ///Vertical position of the frame on the canvas
+    pub y_offset: u32,
+    ///Numerator of the frame delay in seconds
+    pub delay_num: u16,
+    ///Denominator of the frame delay in seconds, 0 is treated as 100
+    pub delay_den: u16,
+    ///What to do with the frame area after the frame is displayed
+    pub dispose_op: DisposeOp,
+    ///How the frame is combined with the canvas
+    pub blend_op: BlendOp,
+}
+
+impl FrameControl {
+    ///How long the frame should be displayed for
+    #[must_use]
+    pub fn delay(&self) -> Duration {
+        let den = if self.delay_den == 0 {
+            100
+        } else {
+            self.delay_den
+        };
+
+        Duration::from_secs_f64(self.delay_num as f64 / den as f64)
+    }
+}
+
+///A single frame of an animation
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Frame {
+    ///Placement and timing of the frame
+    pub control: FrameControl,
+    ///Contents of the frame, the size of the image matches the size of the frame
+    pub image: Image,
+}
+
+///A decoded animated png
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Animation {
+    ///Width of the canvas
+    pub width: u32,
+    ///Height of the canvas
+    pub height: u32,
+    ///Number of times the animation is played, 0 means forever
+    pub num_plays: u32,
+    ///Frames of the animation
+    pub frames: Vec<Frame>,
+    ///The static image shown by decoders without animation support, only present if it is not
+    ///the first frame of the animation
+    pub default_image: Option<Image>,
+}
+
+impl Animation {
+    ///Renders every frame onto the full canvas, applying the blend and dispose operations.
+    ///Returns `Rgba16` images if the frames are 16 bit, `Rgba8` images otherwise. 16 bit samples
+    ///are read and returned in the given byte order
+    ///
+    ///# Panics
+    ///
+    ///Panics if a frame doesn't fit on the canvas, or if the frames are floating point
+    #[must_use]
+    pub fn composite(&self, byte_order: ByteOrder) -> Vec<Image> {
+        assert!(
+            !self.frames.iter().any(|f| f.image.img_type.is_float()),
+            "Floating point frames can't be composited"
+        );
+
+        let is_16 = self
+            .frames
+            .first()
+            .is_some_and(|f| f.image.img_type.is_16_bit());
+
+        let (img_type, sample_size) = if is_16 {
+            (ImageType::Rgba16, 2)
+        } else {
+            (ImageType::Rgba8, 1)
+        };
+        let pixel_size = sample_size * 4;
+
+        let mut canvas = Image {
+            width: self.width,
+            height: self.height,
+            img_type,
+            data: vec![0; self.width as usize * self.height as usize * pixel_size],
+        };
+
+        let mut output = Vec::with_capacity(self.frames.len());
+
+        for (index, frame) in self.frames.iter().enumerate() {
+            let control = frame.control;
+
+            let mut image = frame.image.clone();
+            image.add_channels();
+            image.add_alpha();
+
+            //A previous dispose on the first frame is treated as a background dispose
+            let dispose_op = match control.dispose_op {
+                DisposeOp::Previous if index == 0 => DisposeOp::Background,
+                op => op,
+            };
+
+            let saved = (dispose_op == DisposeOp::Previous).then(|| canvas.data.clone());
+
+            let row_size = control.width as usize * pixel_size;
+            let canvas_row_size = self.width as usize * pixel_size;
+
+            //Rows of the frame area on the canvas
+            let rows = |y: usize| {
+                let start = (control.y_offset as usize + y) * canvas_row_size
+                    + control.x_offset as usize * pixel_size;
+                start..start + row_size
+            };
+
+            for (y, src) in image.data.chunks(row_size).enumerate() {
+                let dst = &mut canvas.data[rows(y)];
+
+                match control.blend_op {
+                    BlendOp::Source => dst.copy_from_slice(src),
+                    BlendOp::Over => {
+                        for (d, s) in dst.chunks_mut(pixel_size).zip(src.chunks(pixel_size)) {
+                            blend_over(d, s, sample_size, byte_order);
+                        }
+                    }
+                }
+            }
+
+            output.push(canvas.clone());
+
+            match dispose_op {
+                DisposeOp::None => {}
+                DisposeOp::Background => {
+                    for y in 0..control.height as usize {
+                        canvas.data[rows(y)].fill(0);
+                    }
+                }
+                DisposeOp::Previous => {
+                    if let Some(saved) = saved {
+                        canvas.data = saved;
+                    }
+                }
+            }
+        }
+
+        output
+    }
+}
+
+///Alpha composites a single rgba pixel over another, 16 bit samples use the given byte order
+fn blend_over(dst: &mut [u8], src: &[u8], sample_size: usize, byte_order: ByteOrder) {
+    let max = if sample_size == 2 { 0xffff } else { 0xff };
+
+    let read = |pixel: &[u8], channel: usize| {
+        if sample_size == 2 {
+            byte_order.read([pixel[channel * 2], pixel[channel * 2 + 1]]) as u64
+        } else {
+            pixel[channel] as u64
+        }
+    };
+
+    let src_alpha = read(src, 3);
+
+    if src_alpha == max {
+        dst.copy_from_slice(src);
+        return;
+    }
+
+    if src_alpha == 0 {
+        return;
+    }
+
+    //Blending as described in the APNG specification
+    let u = src_alpha * max;
+    let v = (max - src_alpha) * read(dst, 3);
+    let alpha = u + v;
+
+    let mut output = [0u64; 4];
+    for (channel, o) in output.iter_mut().enumerate().take(3) {
+        *o = (read(src, channel) * u + read(dst, channel) * v) / alpha;
+    }
+    output[3] = alpha / max;
+
+    for (channel, value) in output.into_iter().enumerate() {
+        if sample_size == 2 {
+            dst[channel * 2..channel * 2 + 2].copy_from_slice(&byte_order.to_bytes(value as u16));
+        } else {
+            dst[channel] = value as u8;
+        }
+    }
+}
@@ -1,10 +1,15 @@
 use crate::{
-    Image, ImageType,
+    Animation, AnimationControl, Background, BlendOp, ByteOrder, Chromaticities,
+    CodingIndependentCodePoints, DisposeOp, Frame, FrameControl, Gamma, IccProfile, Image,
+    ImageType, IndexedImage, InternationalText, MasteringDisplay, Metadata, ModificationTime,
+    Palette, PhysicalDimensions, PixelUnit, RenderingIntent, TextChunk, UnknownChunk,
     helpers::{
-        ChunkType, ColorType, Filtered, Pallete, TrnsPallete, parse_chunk, read_n_const,
-        to_color_type, to_u16, validate_bit_depth,
+        ADAM7_PASSES, ChunkType, ColorType, Pallete, TrnsPallete, get_chunk_type, paeth_predictor,
+        pass_size, scanline_bytes, to_color_type, to_u16, update_crc, validate_bit_depth,
     },
+    metadata::find_orientation,
 };
+use flate2::{Decompress, FlushDecompress, Status};
 use std::io::Read;
 
 static SIGNATURE: &[u8; 8] = &[0x89u8, 0x50, 0x4E, 0x47, 0x0D, 0x0A, 0x1A, 0x0A];
@@ -18,6 +23,20 @@ pub enum Error {
     InvalidChunkType,
     ///CRC of a chunk is incorrect k
     InvalidCrc,
+    ///The stream ended before the image was fully read
+    TruncatedStream,
+    ///The IHDR chunk specifies a color type not defined by the png specification
+    UnknownColorType(u8),
+    ///The image data could not be decompressed
+    ZlibError,
+    ///A pixel references a palette entry that does not exist
+    PaletteIndexOutOfRange(u8),
+    ///The image contains a critical chunk the decoder does not know how to handle
+    UnknownCriticalChunk([u8; 4]),
+    ///Reading from the underlying reader failed
+    Io(std::io::ErrorKind),
+    ///Palette indices were requested from an image that doesn't use indexed color
+    NotIndexed,
     ///Other issue
     InvalidPngData(&'static str),
 }
@@ -28,6 +47,15 @@ impl std::fmt::Display for Error {
             Self::InvalidSignature => write!(f, "invalid signature"),
             Self::InvalidChunkType => write!(f, "invalid chunk type"),
             Self::InvalidCrc => write!(f, "invalid chunk crc"),
+            Self::TruncatedStream => write!(f, "unexpected end of stream"),
+            Self::UnknownColorType(t) => write!(f, "unknown color type {t}"),
+            Self::ZlibError => write!(f, "failed to decompress image data"),
+            Self::PaletteIndexOutOfRange(i) => write!(f, "palette index {i} is out of range"),
+            Self::UnknownCriticalChunk(t) => {
+                write!(f, "unknown critical chunk {}", String::from_utf8_lossy(t))
+            }
+            Self::Io(kind) => write!(f, "io error: {kind}"),
+            Self::NotIndexed => write!(f, "image doesn't use indexed color"),
             Self::InvalidPngData(msg) => write!(f, "invalid png data: {msg}"),
         }
     }
@@ -35,6 +63,55 @@ impl std::fmt::Display for Error {
 
 impl std::error::Error for Error {}
 
+impl From<std::io::Error> for Error {
+    fn from(value: std::io::Error) -> Self {
+        if value.kind() == std::io::ErrorKind::UnexpectedEof {
+            Self::TruncatedStream
+        } else {
+            Self::Io(value.kind())
+        }
+    }
+}
+
+///Settings for png decoding
+#[derive(Default, Debug, Clone, Copy)]
+pub struct DecodingOptions {
+    ///Whether to return grayscale images with a bit depth below 8 as [`ImageType::R1`],
+    ///[`ImageType::R2`] or [`ImageType::R4`] with unscaled samples, instead of scaling them to
+    ///[`ImageType::R8`]. Images with transparency are always scaled
+    pub preserve_bit_depth: bool,
+    ///Byte order of the 16 bit samples of the decoded image
+    pub byte_order: ByteOrder,
+    ///Transfer function to convert the color samples to, based on the `sRGB` and `gAMA` chunks of
+    ///the image. `None` returns the samples as stored
+    pub color_conversion: Option<ColorConversion>,
+    ///Whether to shift 8 and 16 bit samples right to the precision given by the sBIT chunk, e.g.
+    ///returning 10 bit camera data as 0-1023 instead of 0-65535. Has no effect on indexed images,
+    ///alpha channels generated from a tRNS chunk, or if the colors are converted
+    pub shift_significant_bits: bool,
+    ///Whether to rotate and mirror the image according to the orientation stored in its EXIF
+    ///data, so it is returned upright. The orientation in the returned EXIF data is reset to
+    ///normal. Only applies to [`Decoder::decode`] when no rows have been read before, not to
+    ///single rows or animation frames
+    pub auto_orient: bool,
+}
+
+///Transfer function the decoded color samples are converted to.
+///
+///Images with an `sRGB` chunk, or without a `gAMA` chunk, are assumed to use the sRGB transfer
+///function. Chromaticities and ICC profiles are not applied, so only the brightness of the samples
+///is corrected, not their hue
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ColorConversion {
+    ///Linear light, returned as [`ImageType::R32F`], [`ImageType::Ra32F`], [`ImageType::Rgb32F`]
+    ///or [`ImageType::Rgba32F`] with samples ranging from 0 to 1. Grayscale images with a bit
+    ///depth below 8 are not preserved
+    Linear,
+    ///Standard sRGB, keeping the bit depth of the image. Grayscale images with a bit depth below 8
+    ///are not preserved
+    Srgb,
+}
+
 enum TransparencyData {
     None,
     Greyscale(u16),
@@ -42,409 +119,1530 @@ enum TransparencyData {
     Indexed(TrnsPallete),
 }
 
-#[allow(clippy::missing_panics_doc, clippy::too_many_lines)]
-///Parses a png image from a given stream
+///Maximum amount of compressed data read from the reader at once
+const INPUT_BUFFER_SIZE: usize = 32 * 1024;
+
+///Reverses the filtering of a single scanline in place. `previous` is the unfiltered previous
+///scanline of the same pass, all zeros for the first one
+fn unfilter_row(filter_type: u8, row: &mut [u8], previous: &[u8], bpp: usize) -> Result<(), Error> {
+    //Bytes
+    //  |c|b|
+    //  |a|x|
+    //  x = current
+    match filter_type {
+        //None
+        0 => {}
+        //Sub
+        1 => {
+            for i in bpp..row.len() {
+                row[i] = row[i].wrapping_add(row[i - bpp]);
+            }
+        }
+        //Up
+        2 => {
+            for (x, b) in row.iter_mut().zip(previous) {
+                *x = x.wrapping_add(*b);
+            }
+        }
+        //Average
+        3 => {
+            for i in 0..row.len() {
+                let a = if i >= bpp { row[i - bpp] } else { 0 };
+                let floor = u16::midpoint(a as u16, previous[i] as u16);
+
+                row[i] = row[i].wrapping_add(floor as u8);
+            }
+        }
+        //Paeth
+        4 => {
+            for i in 0..row.len() {
+                let (a, c) = if i >= bpp {
+                    (row[i - bpp], previous[i - bpp])
+                } else {
+                    (0, 0)
+                };
+
+                row[i] = row[i].wrapping_add(paeth_predictor(a, previous[i], c));
+            }
+        }
+        _ => return Err(Error::InvalidPngData("Invalid filter method")),
+    }
+
+    Ok(())
+}
+
+///Splits an unfiltered scanline into samples. For bit depths below 8 every sample is unpacked into
+///its own byte and the padding bits at the end of the scanline are dropped, otherwise the data
+///is returned as is
+fn unpack_row(row: &[u8], samples: usize, bit_depth: u8) -> Vec<u8> {
+    if bit_depth >= 8 {
+        return row.to_vec();
+    }
+
+    let mask = (1u8 << bit_depth) - 1;
+    let per_byte = (8 / bit_depth) as usize;
+
+    //Samples are packed starting from the most significant bit
+    row.iter()
+        .flat_map(|byte| {
+            (0..per_byte).map(move |i| (byte >> (8 - bit_depth as usize * (i + 1))) & mask)
+        })
+        .take(samples)
+        .collect()
+}
+
+///Adapts a byte iterator to [`Read`]
+struct IterReader<I>(I);
+
+impl<I: Iterator<Item = u8>> Read for IterReader<I> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let mut read = 0;
+
+        for (b, v) in buf.iter_mut().zip(&mut self.0) {
+            *b = v;
+            read += 1;
+        }
+
+        Ok(read)
+    }
+}
+
+///A streaming png decoder.
 ///
-///# Errors
+///Chunks are read directly from the reader and the image data is decompressed incrementally, so
+///rows can be processed as they are decoded without the whole file or image being in memory.
+///Interlaced images are an exception, as every pass needs to be read before the first row is
+///complete.
 ///
-///May return an error if the data stream doesn't contain a valid png image
+///Small reads are issued to the reader, so unbuffered sources should be wrapped in a
+///[`std::io::BufReader`].
 ///
+///For animated images the rows returned first belong to the default image, further frames are
+///reached with [`Self::next_frame`].
 ///
 ///# Examples
 ///
 ///```no_run
-///# use std::io::Read;
-///# let mut file = std::fs::File::open("...").unwrap();
-///let mut data = Vec::new();
+///let file = std::fs::File::open("...").unwrap();
+///let mut decoder = lunar_png::Decoder::new(std::io::BufReader::new(file)).unwrap();
 ///
-///file.read_to_end(&mut data);
-///
-///let image = lunar_png::decode_png(&mut data.into_iter()).unwrap();
+///while let Some(row) = decoder.next_row().unwrap() {
+///    //Process the row
+///}
 ///```
-pub fn decode_png(stream: &mut impl Iterator<Item = u8>) -> Result<Image, Error> {
-    if &read_n_const(stream) != SIGNATURE {
-        //if signature is incorrect , return a corresponding error
-        return Err(Error::InvalidSignature);
+#[allow(clippy::struct_excessive_bools)]
+pub struct Decoder<R: Read> {
+    reader: R,
+    width: u32,
+    height: u32,
+    bit_depth: u8,
+    color_type: ColorType,
+    interlaced: bool,
+    pallete: Pallete,
+    trns_data: TransparencyData,
+    metadata: Metadata,
+    animation_control: Option<AnimationControl>,
+    //Frame control of the image currently being decoded
+    frame_control: Option<FrameControl>,
+    //Frame control read ahead of the frame data
+    pending_frame: Option<FrameControl>,
+    //Size of the image currently being decoded
+    frame_width: u32,
+    frame_height: u32,
+    //Type of the chunks containing the current image data, IDAT or fdAT
+    data_chunk: [u8; 4],
+    //Expected sequence number of the next fdAT chunk
+    sequence: u32,
+    inflater: Decompress,
+    //Compressed data read from the current data chunk
+    input: Vec<u8>,
+    input_pos: usize,
+    //Bytes of the current data chunk that have not been read yet
+    idat_remaining: u32,
+    //Running CRC of the current data chunk
+    idat_crc: u32,
+    //Whether the first IDAT chunk has been reached
+    idat_started: bool,
+    //Whether all data chunks of the current image have been read
+    idat_done: bool,
+    //Whether the IEND chunk has been read
+    ended: bool,
+    //Scanline buffers, including the filter type byte
+    current: Vec<u8>,
+    previous: Vec<u8>,
+    //Last decoded row in the output format
+    output: Vec<u8>,
+    //Number of rows returned so far
+    row: u32,
+    //Fully decoded image, only used for interlaced images
+    deinterlaced: Option<Vec<u8>>,
+    //Whether palette indices are returned instead of colors
+    keep_indices: bool,
+    options: DecodingOptions,
+    //Converted value of every sample value, only used for color conversion
+    transfer: Vec<f32>,
+    //Data of the bKGD chunk, resolved once the tRNS chunk is known
+    background: Option<Vec<u8>>,
+}
+
+impl<R: Read> Decoder<R> {
+    ///Creates a new decoder, reading the signature and every chunk preceding the image data
+    ///
+    ///# Errors
+    ///
+    ///May return an error if the reader doesn't contain a valid png image
+    pub fn new(reader: R) -> Result<Self, Error> {
+        Self::with_options(reader, &DecodingOptions::default())
     }
 
-    let first_chunk = parse_chunk(stream)?;
+    ///Creates a new decoder with the given settings, reading the signature and every chunk
+    ///preceding the image data
+    ///
+    ///# Errors
+    ///
+    ///May return an error if the reader doesn't contain a valid png image
+    #[allow(clippy::too_many_lines)]
+    pub fn with_options(mut reader: R, options: &DecodingOptions) -> Result<Self, Error> {
+        let mut signature = [0; 8];
+        reader.read_exact(&mut signature)?;
+
+        if &signature != SIGNATURE {
+            //if signature is incorrect , return a corresponding error
+            return Err(Error::InvalidSignature);
+        }
+
+        let (length, chunk_type) = read_chunk_header(&mut reader)?;
+
+        //Return an error if the first chunk is not a header
+        if &chunk_type != b"IHDR" {
+            return Err(Error::InvalidPngData(
+                "Invalid png file, IHDR must be the first chunk",
+            ));
+        }
 
-    //Return an error if the first chunk is not a header
-    if first_chunk.chunk_type != ChunkType::IHDR {
-        return Err(Error::InvalidPngData(
-            "Invalid png file, IHDR must be the first chunk",
-        ));
+        let data = read_chunk_data(&mut reader, chunk_type, length)?;
+
+        let [
+            w0,
+            w1,
+            w2,
+            w3,
+            h0,
+            h1,
+            h2,
+            h3,
+            bit_depth,
+            color_type,
+            compression_method,
+            filter_method,
+            interlace_method,
+        ] = data[..]
+        else {
+            return Err(Error::InvalidPngData("Invalid IHDR length"));
+        };
+
+        let width = u32::from_be_bytes([w0, w1, w2, w3]);
+        let height = u32::from_be_bytes([h0, h1, h2, h3]);
+        let color_type = to_color_type(color_type)?;
+
+        //Dimensions are limited to 2^31 - 1
+        if width == 0 || height == 0 || width > i32::MAX as u32 || height > i32::MAX as u32 {
+            return Err(Error::InvalidPngData("Invalid image dimensions"));
+        }
+
+        if interlace_method > 1 {
+            return Err(Error::InvalidPngData("Invalid interlace method"));
+        }
+
+        if filter_method != 0 {
+            return Err(Error::InvalidPngData("Invalid filter method"));
+        }
+
+        if !validate_bit_depth(color_type, bit_depth) {
+            return Err(Error::InvalidPngData("Invalid bit depth for color type"));
+        }
+
+        if compression_method != 0 {
+            return Err(Error::InvalidPngData("Invalid compression method"));
+        }
+
+        let mut decoder = Self {
+            reader,
+            width,
+            height,
+            bit_depth,
+            color_type,
+            interlaced: interlace_method == 1,
+            pallete: Pallete::empty(),
+            trns_data: TransparencyData::None,
+            metadata: Metadata::default(),
+            animation_control: None,
+            frame_control: None,
+            pending_frame: None,
+            frame_width: width,
+            frame_height: height,
+            data_chunk: *b"IDAT",
+            sequence: 0,
+            inflater: Decompress::new(true),
+            input: Vec::new(),
+            input_pos: 0,
+            idat_remaining: 0,
+            idat_crc: 0,
+            idat_started: false,
+            idat_done: false,
+            ended: false,
+            current: Vec::new(),
+            previous: Vec::new(),
+            output: Vec::new(),
+            row: 0,
+            deinterlaced: None,
+            keep_indices: false,
+            options: DecodingOptions {
+                //Samples are converted at 8 bits
+                preserve_bit_depth: options.preserve_bit_depth
+                    && options.color_conversion.is_none(),
+                shift_significant_bits: options.shift_significant_bits
+                    && options.color_conversion.is_none(),
+                ..*options
+            },
+            transfer: Vec::new(),
+            background: None,
+        };
+
+        //Read everything up to the image data
+        loop {
+            let (length, chunk_type) = read_chunk_header(&mut decoder.reader)?;
+
+            if &chunk_type == b"IDAT" {
+                decoder.idat_started = true;
+                //A frame control before the image data makes the default image the first frame
+                decoder.frame_control = decoder.pending_frame.take();
+                decoder.start_data_chunk(length)?;
+                break;
+            }
+
+            let data = read_chunk_data(&mut decoder.reader, chunk_type, length)?;
+            decoder.handle_chunk(chunk_type, data)?;
+
+            if decoder.ended {
+                return Err(Error::InvalidPngData("Missing IDAT chunk"));
+            }
+        }
+
+        decoder.resolve_background();
+        decoder.build_transfer();
+
+        Ok(decoder)
     }
 
-    let mut data_iter = first_chunk.data.into_iter();
-    let width = u32::from_be_bytes(read_n_const(&mut data_iter));
-    let height = u32::from_be_bytes(read_n_const(&mut data_iter));
+    ///Width of the image
+    #[must_use]
+    pub const fn width(&self) -> u32 {
+        self.width
+    }
+
+    ///Height of the image
+    #[must_use]
+    pub const fn height(&self) -> u32 {
+        self.height
+    }
 
-    let bit_depth = data_iter.next().unwrap();
-    let color_type = to_color_type(data_iter.next().unwrap());
-    let compression_method = data_iter.next().unwrap();
-    let filter_method = data_iter.next().unwrap();
-    let interlace_method = data_iter.next().unwrap();
+    ///Type of the decoded image, the format of the rows returned by [`Self::next_row`]
+    #[must_use]
+    pub const fn img_type(&self) -> ImageType {
+        let img_type = self.stored_img_type();
 
-    if interlace_method != 0 {
-        todo!("Interlacing not yet supported");
+        match (self.options.color_conversion, img_type.channels()) {
+            (Some(ColorConversion::Linear), 1) => ImageType::R32F,
+            (Some(ColorConversion::Linear), 2) => ImageType::Ra32F,
+            (Some(ColorConversion::Linear), 3) => ImageType::Rgb32F,
+            (Some(ColorConversion::Linear), _) => ImageType::Rgba32F,
+            _ => img_type,
+        }
+    }
+
+    ///Type of the image before color conversion
+    const fn stored_img_type(&self) -> ImageType {
+        let has_trns = !matches!(self.trns_data, TransparencyData::None);
+
+        match (self.color_type, self.bit_depth == 16, has_trns) {
+            (ColorType::Greyscale, false, false) if self.options.preserve_bit_depth => {
+                match self.bit_depth {
+                    1 => ImageType::R1,
+                    2 => ImageType::R2,
+                    4 => ImageType::R4,
+                    _ => ImageType::R8,
+                }
+            }
+            (ColorType::Greyscale, false, false) => ImageType::R8,
+            (ColorType::Greyscale, true, false) => ImageType::R16,
+            (ColorType::Greyscale, false, true) | (ColorType::GreyscaleAlpha, false, _) => {
+                ImageType::Ra8
+            }
+            (ColorType::Greyscale, true, true) | (ColorType::GreyscaleAlpha, true, _) => {
+                ImageType::Ra16
+            }
+            (ColorType::Truecolor | ColorType::IndexedColor, false, false) => ImageType::Rgb8,
+            (ColorType::Truecolor, true, false) => ImageType::Rgb16,
+            (ColorType::Truecolor | ColorType::IndexedColor, false, true)
+            | (ColorType::TruecolorAlpha, false, _) => ImageType::Rgba8,
+            (ColorType::Truecolor, true, true) | (ColorType::TruecolorAlpha, true, _) => {
+                ImageType::Rgba16
+            }
+            //Indexed images can't be 16 bit
+            (ColorType::IndexedColor, true, _) => unreachable!(),
+        }
     }
 
-    if filter_method != 0 {
-        return Err(Error::InvalidPngData("Invalid filter method"));
+    ///Metadata read so far. Chunks after the image data are only available once every row has
+    ///been read, or for animated images once [`Self::next_frame`] returns `None`
+    #[must_use]
+    pub const fn metadata(&self) -> &Metadata {
+        &self.metadata
     }
 
-    if !validate_bit_depth(color_type, bit_depth) {
-        return Err(Error::InvalidPngData("Invalid bit depth for color type"));
+    ///Animation information, `None` if the image is not animated
+    #[must_use]
+    pub const fn animation_control(&self) -> Option<AnimationControl> {
+        self.animation_control
     }
 
-    if compression_method != 0 {
-        return Err(Error::InvalidPngData("Invalid compression method"));
+    ///Frame control of the image currently being decoded. `None` if the image is not animated,
+    ///or if the default image is not part of the animation
+    #[must_use]
+    pub const fn frame_control(&self) -> Option<FrameControl> {
+        self.frame_control
     }
 
-    //Start the chunk reading loop
-    let mut png_data = Vec::new();
+    ///Moves on to the next frame of an animated image, skipping any rows of the current image
+    ///that have not been read. Rows of the frame are then read with [`Self::next_row`].
+    ///Returns `None` once there are no frames left
+    ///
+    ///# Errors
+    ///
+    ///May return an error if the reader doesn't contain a valid png image
+    pub fn next_frame(&mut self) -> Result<Option<FrameControl>, Error> {
+        self.finish_image()?;
 
-    let mut pallete = Pallete::empty();
-    let mut trns_data = TransparencyData::None;
+        while !self.ended {
+            let (length, chunk_type) = read_chunk_header(&mut self.reader)?;
 
-    loop {
-        //Get the chunk
-        let chunk = parse_chunk(stream)?;
+            if &chunk_type == b"fdAT"
+                && let Some(control) = self.pending_frame.take()
+            {
+                self.frame_control = Some(control);
+                self.frame_width = control.width;
+                self.frame_height = control.height;
 
-        //check if it's the last chunk
-        if chunk.chunk_type == ChunkType::IEND {
-            break;
+                self.data_chunk = chunk_type;
+                self.inflater = Decompress::new(true);
+                self.input.clear();
+                self.input_pos = 0;
+                self.idat_done = false;
+                self.row = 0;
+                self.deinterlaced = None;
+
+                self.start_data_chunk(length)?;
+
+                return Ok(Some(control));
+            }
+
+            let data = read_chunk_data(&mut self.reader, chunk_type, length)?;
+            self.handle_chunk(chunk_type, data)?;
         }
 
-        match chunk.chunk_type {
-            ChunkType::PLTE => {
-                //Get the palette
-                pallete = Pallete::new(chunk.data);
+        Ok(None)
+    }
+
+    ///Decodes the next row of the image, returns `None` once every row has been read and the rest
+    ///of the file has been processed
+    ///
+    ///# Errors
+    ///
+    ///May return an error if the reader doesn't contain a valid png image
+    pub fn next_row(&mut self) -> Result<Option<&[u8]>, Error> {
+        if self.row == self.frame_height {
+            //Further frames may follow the image data
+            if self.animation_control.is_some() {
+                self.finish_image()?;
+            } else {
+                self.finish()?;
+            }
+
+            return Ok(None);
+        }
+
+        let row_size = self.frame_width as usize * self.pixel_size();
+        let index = self.row as usize * row_size;
+        self.row += 1;
+
+        if self.interlaced {
+            if self.deinterlaced.is_none() {
+                self.deinterlaced = Some(self.read_interlaced()?);
+            }
+
+            return Ok(self
+                .deinterlaced
+                .as_deref()
+                .map(|data| &data[index..index + row_size]));
+        }
+
+        if index == 0 {
+            self.start_pass();
+        }
+        self.read_scanline(self.frame_width)?;
+
+        Ok(Some(&self.output))
+    }
+
+    ///Decodes the rest of the image. If rows were already read with [`Self::next_row`], the
+    ///returned image only contains the remaining rows
+    ///
+    ///# Errors
+    ///
+    ///May return an error if the reader doesn't contain a valid png image
+    pub fn decode(mut self) -> Result<(Image, Metadata), Error> {
+        let whole = self.row == 0;
+
+        let mut image = self.read_image()?;
+        self.finish()?;
+
+        if self.options.auto_orient
+            && whole
+            && let Some(orientation) = self.metadata.orientation()
+        {
+            image.apply_orientation(orientation);
+
+            //The image is upright now, so it shouldn't be rotated again
+            if let Some(exif) = &mut self.metadata.exif
+                && let Some((offset, big_endian)) = find_orientation(exif)
+            {
+                let normal = if big_endian {
+                    1u16.to_be_bytes()
+                } else {
+                    1u16.to_le_bytes()
+                };
+                exif[offset..offset + 2].copy_from_slice(&normal);
             }
-            //Data
+        }
+
+        Ok((image, self.metadata))
+    }
+
+    ///Decodes the rest of an indexed image, keeping the palette indices instead of expanding
+    ///them into colors. If rows were already read with [`Self::next_row`], the returned image only
+    ///contains the remaining rows
+    ///
+    ///# Errors
+    ///
+    ///Returns [`Error::NotIndexed`] if the image doesn't use indexed color, may return an error if
+    ///the reader doesn't contain a valid png image
+    pub fn decode_indexed(mut self) -> Result<(IndexedImage, Metadata), Error> {
+        if !matches!(self.color_type, ColorType::IndexedColor) {
+            return Err(Error::NotIndexed);
+        }
+
+        self.keep_indices = true;
+
+        let image = self.read_image()?;
+        self.finish()?;
+
+        let colors = self.pallete.colors();
+
+        //Entries past the end of the palette have no color to apply to
+        let mut alpha = match &self.trns_data {
+            TransparencyData::Indexed(trns) => trns.values().to_vec(),
+            _ => Vec::new(),
+        };
+        alpha.truncate(colors.len());
+
+        let palette = Palette { colors, alpha };
+
+        let image = IndexedImage {
+            width: image.width,
+            height: image.height,
+            bit_depth: self.bit_depth,
+            data: image.data,
+            palette,
+        };
+
+        Ok((image, self.metadata))
+    }
+
+    ///Size of a single pixel of the returned rows
+    const fn pixel_size(&self) -> usize {
+        if self.keep_indices {
+            1
+        } else {
+            self.img_type().bytes_per_pixel()
+        }
+    }
+
+    ///Reads the remaining rows of the current image
+    fn read_image(&mut self) -> Result<Image, Error> {
+        let mut data = Vec::new();
+        let height = self.frame_height - self.row;
+
+        while let Some(row) = self.next_row()? {
+            data.extend_from_slice(row);
+        }
+
+        Ok(Image {
+            width: self.frame_width,
+            height,
+            img_type: self.img_type(),
+            data,
+        })
+    }
+
+    ///Processes a chunk that is not part of the image data
+    #[allow(clippy::too_many_lines)]
+    fn handle_chunk(&mut self, chunk_type: [u8; 4], data: Vec<u8>) -> Result<(), Error> {
+        //Chunks that affect pixel values can't come after the image data
+        let before_data = !self.idat_started;
+
+        match get_chunk_type(chunk_type)? {
+            ChunkType::IEND => self.ended = true,
+            ChunkType::IHDR => return Err(Error::InvalidPngData("Multiple IHDR chunks")),
             ChunkType::IDAT => {
-                png_data.extend_from_slice(&chunk.data);
+                return Err(Error::InvalidPngData("IDAT chunks must be consecutive"));
+            }
+            ChunkType::PLTE if before_data => {
+                //Get the palette
+                self.pallete = Pallete::new(data);
             }
             //Transparency
-            ChunkType::tRNS => {
-                let mut data = chunk.data.into_iter();
-                trns_data = match color_type {
-                    ColorType::Greyscale => {
-                        TransparencyData::Greyscale(u16::from_be_bytes(read_n_const(&mut data)))
-                    }
-                    ColorType::Truecolor => TransparencyData::Truecolor(
-                        u16::from_be_bytes(read_n_const(&mut data)),
-                        u16::from_be_bytes(read_n_const(&mut data)),
-                        u16::from_be_bytes(read_n_const(&mut data)),
-                    ),
-                    ColorType::IndexedColor => {
-                        TransparencyData::Indexed(TrnsPallete::new(data.collect()))
+            ChunkType::tRNS if before_data => {
+                let value = |i: usize| {
+                    data.get(i..i + 2)
+                        .map(|v| u16::from_be_bytes([v[0], v[1]]))
+                        .ok_or(Error::TruncatedStream)
+                };
+
+                self.trns_data = match self.color_type {
+                    ColorType::Greyscale => TransparencyData::Greyscale(value(0)?),
+                    ColorType::Truecolor => {
+                        TransparencyData::Truecolor(value(0)?, value(2)?, value(4)?)
                     }
+                    ColorType::IndexedColor => TransparencyData::Indexed(TrnsPallete::new(data)),
                     _ => return Err(Error::InvalidPngData("Image can not contain tRNS chunk")),
                 }
             }
+            ChunkType::sBIT if before_data => {
+                let (channels, max) = match self.color_type {
+                    ColorType::IndexedColor => (3, 8),
+                    color_type => (color_type.channels() as usize, self.bit_depth),
+                };
+
+                //Malformed chunks are ignored, leaving the samples unshifted
+                if data.len() == channels && data.iter().all(|b| (1..=max).contains(b)) {
+                    self.metadata.significant_bits = Some(data);
+                }
+            }
+            //Malformed color space chunks are ignored, the image is shown without them
+            ChunkType::gAMA if before_data => {
+                if let [g0, g1, g2, g3] = data[..] {
+                    self.metadata.gamma = Some(Gamma(u32::from_be_bytes([g0, g1, g2, g3])));
+                }
+            }
+            ChunkType::cHRM if before_data && data.len() == 32 => {
+                let value = |i: usize| {
+                    u32::from_be_bytes([
+                        data[i * 4],
+                        data[i * 4 + 1],
+                        data[i * 4 + 2],
+                        data[i * 4 + 3],
+                    ])
+                };
+
+                self.metadata.chromaticities = Some(Chromaticities {
+                    white_point: (value(0), value(1)),
+                    red: (value(2), value(3)),
+                    green: (value(4), value(5)),
+                    blue: (value(6), value(7)),
+                });
+            }
+            ChunkType::sRGB if before_data => {
+                let intent = match data[..] {
+                    [0] => RenderingIntent::Perceptual,
+                    [1] => RenderingIntent::RelativeColorimetric,
+                    [2] => RenderingIntent::Saturation,
+                    [3] => RenderingIntent::AbsoluteColorimetric,
+                    _ => return Ok(()),
+                };
+
+                self.metadata.srgb = Some(intent);
+            }
+            ChunkType::iCCP if before_data => {
+                let null = data
+                    .iter()
+                    .position(|b| *b == 0)
+                    .filter(|n| (1..=79).contains(n));
+
+                //The byte after the name is the compression method
+                if let Some(null) = null
+                    && data.get(null + 1) == Some(&0)
+                    && let Ok(profile) = inflate(&data[null + 2..])
+                {
+                    self.metadata.icc_profile = Some(IccProfile {
+                        name: latin1(&data[..null]),
+                        data: profile,
+                    });
+                }
+            }
+            ChunkType::cICP if before_data => {
+                //Only rgb is supported, so the matrix coefficients have to be 0
+                let [primaries, transfer, 0, full_range @ (0 | 1)] = data[..] else {
+                    return Ok(());
+                };
+
+                self.metadata.cicp = Some(CodingIndependentCodePoints {
+                    color_primaries: primaries,
+                    transfer_characteristics: transfer,
+                    matrix_coefficients: 0,
+                    full_range: full_range == 1,
+                });
+            }
+            ChunkType::mDCv if before_data && data.len() == 24 => {
+                let value = |i: usize| u16::from_be_bytes([data[i * 2], data[i * 2 + 1]]);
+                let luminance =
+                    |i: usize| u32::from_be_bytes([data[i], data[i + 1], data[i + 2], data[i + 3]]);
+
+                self.metadata.mastering_display = Some(MasteringDisplay {
+                    primaries: [
+                        (value(0), value(1)),
+                        (value(2), value(3)),
+                        (value(4), value(5)),
+                    ],
+                    white_point: (value(6), value(7)),
+                    max_luminance: luminance(16),
+                    min_luminance: luminance(20),
+                });
+            }
+            ChunkType::bKGD if before_data => self.background = Some(data),
+            ChunkType::pHYs if before_data => {
+                //Malformed chunks are ignored, the dimensions don't affect the image
+                let [x0, x1, x2, x3, y0, y1, y2, y3, unit @ (0 | 1)] = data[..] else {
+                    return Ok(());
+                };
+
+                self.metadata.physical_dimensions = Some(PhysicalDimensions {
+                    x: u32::from_be_bytes([x0, x1, x2, x3]),
+                    y: u32::from_be_bytes([y0, y1, y2, y3]),
+                    unit: if unit == 1 {
+                        PixelUnit::Meter
+                    } else {
+                        PixelUnit::Unknown
+                    },
+                });
+            }
+            ChunkType::acTL if before_data => {
+                let [f0, f1, f2, f3, p0, p1, p2, p3] = data[..] else {
+                    return Err(Error::InvalidPngData("Invalid acTL chunk"));
+                };
+
+                self.animation_control = Some(AnimationControl {
+                    num_frames: u32::from_be_bytes([f0, f1, f2, f3]),
+                    num_plays: u32::from_be_bytes([p0, p1, p2, p3]),
+                });
+            }
+            ChunkType::fcTL if self.animation_control.is_some() => {
+                let (sequence, control) = parse_frame_control(&data)?;
+
+                let region = (
+                    control.x_offset,
+                    control.y_offset,
+                    control.width,
+                    control.height,
+                );
+
+                //A frame control before the image data describes the default image
+                if before_data && region != (0, 0, self.width, self.height) {
+                    return Err(Error::InvalidPngData(
+                        "First frame has to cover the whole canvas",
+                    ));
+                }
+
+                //Frames have to fit on the canvas
+                if control.x_offset as u64 + control.width as u64 > self.width as u64
+                    || control.y_offset as u64 + control.height as u64 > self.height as u64
+                {
+                    return Err(Error::InvalidPngData("Frame is outside of the canvas"));
+                }
+
+                self.sequence = sequence.wrapping_add(1);
+                self.pending_frame = Some(control);
+            }
+            ChunkType::tIME => {
+                //Malformed or out of range times are ignored
+                let [y0, y1, month, day, hour, minute, second] = data[..] else {
+                    return Ok(());
+                };
+
+                let time = ModificationTime {
+                    year: u16::from_be_bytes([y0, y1]),
+                    month,
+                    day,
+                    hour,
+                    minute,
+                    second,
+                };
+
+                if time.is_valid() {
+                    self.metadata.modification_time = Some(time);
+                }
+            }
+            //Only the first chunk starting with a TIFF header is kept
+            ChunkType::eXIf
+                if self.metadata.exif.is_none()
+                    && (data.starts_with(b"II*\0") || data.starts_with(b"MM\0*")) =>
+            {
+                self.metadata.exif = Some(data);
+            }
+            ChunkType::tEXt | ChunkType::zTXt | ChunkType::iTXt => {
+                //Malformed text chunks are skipped, they don't affect the image
+                if let Ok(text) = parse_text(chunk_type, &data) {
+                    self.metadata.text.push(text);
+                }
+            }
+            ChunkType::Unknown(chunk_type) => {
+                let chunk = UnknownChunk { chunk_type, data };
+
+                //Unknown ancillary chunks can be safely skipped
+                if !chunk.is_ancillary() {
+                    return Err(Error::UnknownCriticalChunk(chunk_type));
+                }
+
+                self.metadata.unknown_chunks.push(chunk);
+            }
             _ => {}
         }
+
+        Ok(())
     }
 
-    //Decompress the data
-    let mut data = Vec::new();
-    let mut decoder = flate2::read::ZlibDecoder::new(&png_data[..]);
-    let _ = decoder.read_to_end(&mut data).unwrap();
-
-    let scanline_len = match color_type {
-        ColorType::IndexedColor | ColorType::Greyscale => bit_depth as u32 * width / 8 + 1,
-        ColorType::Truecolor => bit_depth as u32 * 3 * width / 8 + 1,
-        ColorType::GreyscaleAlpha => (bit_depth as u32 * 2 * width) / 8 + 1,
-        ColorType::TruecolorAlpha => ((bit_depth as u32 * 4 * width) / 8) + 1,
-    };
+    ///Starts reading a data chunk, whose header has already been read
+    fn start_data_chunk(&mut self, length: u32) -> Result<(), Error> {
+        self.idat_remaining = length;
+        self.idat_crc = update_crc(u32::MAX, &self.data_chunk);
 
-    //FILTERING!
-    let mut filtered = Filtered {
-        data,
-        color_type,
-        scanline_len,
-        bit_depth,
-        ignore_0: true,
-    };
+        //fdAT chunks start with a sequence number
+        if &self.data_chunk == b"fdAT" {
+            if length < 4 {
+                return Err(Error::InvalidPngData("Invalid fdAT chunk"));
+            }
 
-    let mut unfiltered_data = Vec::new();
-    let mut filter_method = 0;
+            let mut sequence = [0; 4];
+            self.reader.read_exact(&mut sequence)?;
 
-    for (index, val) in filtered.data.clone().iter().enumerate() {
-        if index % scanline_len as usize == 0 {
-            filter_method = *val;
-            continue;
+            self.idat_crc = update_crc(self.idat_crc, &sequence);
+            self.idat_remaining -= 4;
+
+            if u32::from_be_bytes(sequence) != self.sequence {
+                return Err(Error::InvalidPngData("Invalid APNG sequence number"));
+            }
+            self.sequence = self.sequence.wrapping_add(1);
         }
 
-        match filter_method {
-            //None
-            0 => {
-                //Do nothing
-                unfiltered_data.push(*val);
+        Ok(())
+    }
+
+    ///Reads the next piece of compressed data, moving on to the next data chunk if needed
+    fn refill_input(&mut self) -> Result<(), Error> {
+        while self.idat_remaining == 0 {
+            let mut crc = [0; 4];
+            self.reader.read_exact(&mut crc)?;
+
+            if u32::from_be_bytes(crc) != self.idat_crc ^ u32::MAX {
+                return Err(Error::InvalidCrc);
             }
-            //Sub
-            1 => {
-                let o = ((*val as u16 + filtered.get_a(index) as u16) % 256) as u8;
-                filtered.set(index, o);
-                unfiltered_data.push(o);
+
+            let (length, chunk_type) = read_chunk_header(&mut self.reader)?;
+
+            if chunk_type == self.data_chunk {
+                self.start_data_chunk(length)?;
+            } else {
+                self.idat_done = true;
+
+                let data = read_chunk_data(&mut self.reader, chunk_type, length)?;
+                return self.handle_chunk(chunk_type, data);
             }
-            //up
-            2 => {
-                let o = ((*val as u16 + filtered.get_b(index) as u16) % 256) as u8;
-                filtered.set(index, o);
+        }
+
+        let len = INPUT_BUFFER_SIZE.min(self.idat_remaining as usize);
 
-                unfiltered_data.push(o);
+        self.input.resize(len, 0);
+        self.reader.read_exact(&mut self.input)?;
+
+        self.idat_crc = update_crc(self.idat_crc, &self.input);
+        self.idat_remaining -= len as u32;
+        self.input_pos = 0;
+
+        Ok(())
+    }
+
+    ///Decompresses exactly `len` bytes of image data into `output`
+    fn read_image_data(&mut self, output: &mut Vec<u8>, len: usize) -> Result<(), Error> {
+        output.clear();
+
+        while output.len() < len {
+            if self.input_pos == self.input.len() && !self.idat_done {
+                self.refill_input()?;
+                continue;
             }
-            //average
-            3 => {
-                let a = filtered.get_a(index) as u16;
-                let b = filtered.get_b(index) as u16;
 
-                let floor = u16::midpoint(a, b);
+            //Grow the output gradually, so that bogus dimensions don't cause huge allocations
+            let start = output.len();
+            output.resize(len.min(start + INPUT_BUFFER_SIZE), 0);
+
+            let total_in = self.inflater.total_in();
+            let total_out = self.inflater.total_out();
 
-                let o = ((*val as u16 + floor) % 256) as u8;
-                filtered.set(index, o);
+            let status = self
+                .inflater
+                .decompress(
+                    &self.input[self.input_pos..],
+                    &mut output[start..],
+                    FlushDecompress::None,
+                )
+                .map_err(|_| Error::ZlibError)?;
 
-                unfiltered_data.push(o);
+            let consumed = (self.inflater.total_in() - total_in) as usize;
+            let produced = (self.inflater.total_out() - total_out) as usize;
+
+            self.input_pos += consumed;
+            output.truncate(start + produced);
+
+            if status == Status::StreamEnd && output.len() < len || consumed == 0 && produced == 0 {
+                return Err(Error::InvalidPngData("Not enough image data"));
             }
-            //paeth
-            4 => {
-                let o = ((*val as u16 + filtered.paeth(index) as u16) % 256) as u8;
-                filtered.set(index, o);
+        }
 
-                unfiltered_data.push(o);
+        Ok(())
+    }
+
+    ///Resets the previous scanline, must be called before the first scanline of every pass
+    fn start_pass(&mut self) {
+        self.previous.clear();
+    }
+
+    ///Reads and unfilters a scanline of the given width, converting it into the output format
+    fn read_scanline(&mut self, width: u32) -> Result<(), Error> {
+        let row_len = scanline_bytes(width, self.color_type, self.bit_depth);
+        let bpp = (self.color_type.channels() as usize * self.bit_depth as usize / 8).max(1);
+
+        let mut current = std::mem::take(&mut self.current);
+        self.read_image_data(&mut current, row_len + 1)?;
+
+        //The first scanline of a pass is filtered against zeros. The buffer is only allocated once
+        //the data is actually read, so bogus dimensions don't cause huge allocations
+        self.previous.resize(current.len(), 0);
+        unfilter_row(current[0], &mut current[1..], &self.previous[1..], bpp)?;
+
+        let samples = unpack_row(
+            &current[1..],
+            width as usize * self.color_type.channels() as usize,
+            self.bit_depth,
+        );
+
+        self.current = std::mem::replace(&mut self.previous, current);
+
+        let mut output = std::mem::take(&mut self.output);
+        output.clear();
+        let result = self.convert_row(&samples, &mut output);
+        self.output = output;
+
+        if !self.transfer.is_empty() && !self.keep_indices {
+            self.convert_colors();
+        }
+
+        result
+    }
+
+    ///Number of bits every channel is shifted right by to return the significant bits only
+    fn significant_shifts(&self) -> [u8; 4] {
+        let mut shifts = [0; 4];
+
+        if self.options.shift_significant_bits
+            && self.bit_depth >= 8
+            && let Some(bits) = &self.metadata.significant_bits
+        {
+            for (shift, bits) in shifts.iter_mut().zip(bits) {
+                *shift = self.bit_depth - bits;
             }
-            _ => return Err(Error::InvalidPngData("Invalid filter method")),
         }
+
+        shifts
     }
 
-    let mut img = match color_type {
-        ColorType::Greyscale => {
-            let new_data = match bit_depth {
-                1 | 2 | 4 => {
-                    let mut o = Vec::new();
+    ///Converts the data of the bKGD chunk into the sample range of the decoded image. Malformed
+    ///chunks are ignored
+    fn resolve_background(&mut self) {
+        let Some(data) = self.background.take() else {
+            return;
+        };
 
-                    //Iterate over N bits (N = bit_depth)
-                    //Extract data i & 2^N - 1 << iter_num
-                    //Normalize over 0-255 (255 / ((2 << N) - 1) * num)
-                    for i in &unfiltered_data {
-                        let mut d = Vec::new();
-                        for index in 0..(8 / bit_depth) {
-                            //Complete indexer
-                            let indexer: u8 = ((1 << bit_depth) - 1) << (index * bit_depth);
-                            let num = (i & indexer) >> (index * bit_depth);
-                            let normalized = (255 / ((1 << bit_depth) - 1)) * num;
-
-                            d.push(normalized);
-                        }
-
-                        d.reverse();
-                        o.append(&mut d);
+        let max = ((1u32 << self.bit_depth) - 1) as u16;
+        let shifts = self.significant_shifts();
+
+        //Shifted like the samples of the same channel
+        let value = |i: usize| {
+            data.get(i * 2..i * 2 + 2)
+                .map(|v| u16::from_be_bytes([v[0], v[1]]))
+                .filter(|v| *v <= max)
+                .map(|v| v >> shifts[i])
+        };
+
+        let background = match self.color_type {
+            ColorType::IndexedColor => match data[..] {
+                [index] => self.pallete.get(index).ok().map(|color| {
+                    Background::Rgb([color[0] as u16, color[1] as u16, color[2] as u16])
+                }),
+                _ => None,
+            },
+            ColorType::Greyscale | ColorType::GreyscaleAlpha if data.len() == 2 => {
+                value(0).map(|value| {
+                    //Low bit depths are scaled like the samples
+                    if self.bit_depth < 8 && self.stored_img_type().bit_depth() == 8 {
+                        Background::Gray(value * (255 / max))
+                    } else {
+                        Background::Gray(value)
                     }
-                    o
+                })
+            }
+            ColorType::Truecolor | ColorType::TruecolorAlpha if data.len() == 6 => {
+                match (value(0), value(1), value(2)) {
+                    (Some(r), Some(g), Some(b)) => Some(Background::Rgb([r, g, b])),
+                    _ => None,
                 }
-                16 | 8 => unfiltered_data,
-                _ => return Err(Error::InvalidPngData("Invalid bit depth")),
-            };
+            }
+            _ => None,
+        };
 
-            if bit_depth == 16 {
-                let mut img = Image {
-                    width,
-                    height,
-                    img_type: ImageType::R16,
-                    data: new_data,
-                };
+        self.metadata.background = background;
+    }
 
-                //if a transparency chunck is found, modify the image type and stick on some bytes
-                //at the end of every pixel
-                match trns_data {
-                    TransparencyData::Greyscale(value) => {
-                        img.img_type = ImageType::Rgba16;
-                        img.data = img
-                            .data
-                            .chunks(2)
-                            .flat_map(|c| {
-                                let v = if to_u16(c[0], c[1]) == value { 0 } else { 0xff };
-                                [c[0], c[1], v, v]
-                            })
-                            .collect();
-                    }
-                    TransparencyData::None => {}
-                    _ => unreachable!(),
+    ///Fills the transfer table used for color conversion, mapping every sample value to the
+    ///requested transfer function
+    fn build_transfer(&mut self) {
+        let Some(conversion) = self.options.color_conversion else {
+            return;
+        };
+
+        let max = if self.stored_img_type().is_16_bit() {
+            u16::MAX
+        } else {
+            u8::MAX as u16
+        };
+
+        //The gAMA chunk stores the encoding exponent, times 100000
+        let gamma = match self.metadata.gamma {
+            Some(gamma) if self.metadata.srgb.is_none() && gamma.0 != 0 => {
+                Some(gamma.value() as f32)
+            }
+            _ => None,
+        };
+
+        self.transfer = (0..=max)
+            .map(|v| {
+                let v = v as f32 / max as f32;
+
+                let linear = gamma.map_or_else(|| srgb_to_linear(v), |gamma| v.powf(1.0 / gamma));
+
+                match conversion {
+                    ColorConversion::Linear => linear,
+                    ColorConversion::Srgb => linear_to_srgb(linear),
                 }
+            })
+            .collect();
+    }
+
+    ///Converts the color samples of the last decoded row using the transfer table
+    fn convert_colors(&mut self) {
+        let img_type = self.stored_img_type();
+        let channels = img_type.channels();
+        let has_alpha = channels.is_multiple_of(2);
+        let byte_order = self.options.byte_order;
+
+        let samples: Vec<u16> = if img_type.is_16_bit() {
+            self.output
+                .chunks_exact(2)
+                .map(|s| byte_order.read([s[0], s[1]]))
+                .collect()
+        } else {
+            self.output.iter().map(|s| *s as u16).collect()
+        };
+
+        let max = (self.transfer.len() - 1) as u16 as f32;
+
+        self.output.clear();
+
+        for (i, sample) in samples.into_iter().enumerate() {
+            let alpha = has_alpha && i % channels == channels - 1;
+
+            if self.options.color_conversion == Some(ColorConversion::Linear) {
+                let value = if alpha {
+                    sample as f32 / max
+                } else {
+                    self.transfer[sample as usize]
+                };
 
-                img
+                self.output.extend_from_slice(&value.to_ne_bytes());
             } else {
-                let mut img = Image {
-                    width,
-                    height,
-                    img_type: ImageType::R8,
-                    data: new_data,
+                let value = if alpha {
+                    sample
+                } else {
+                    (self.transfer[sample as usize] * max).round() as u16
                 };
 
-                match trns_data {
-                    TransparencyData::Greyscale(value) => {
-                        img.img_type = ImageType::Ra8;
-                        img.data = img
-                            .data
-                            .iter()
-                            .flat_map(|i| {
-                                let v = if *i as u16 == value { 0 } else { 0xff };
-                                [*i, v]
-                            })
-                            .collect();
-                    }
-                    TransparencyData::None => {}
-                    _ => unreachable!(),
+                if img_type.is_16_bit() {
+                    self.output.extend_from_slice(&byte_order.to_bytes(value));
+                } else {
+                    self.output.push(value as u8);
                 }
-                img
             }
         }
+    }
 
-        ColorType::Truecolor => {
-            let mut img = Image {
-                width,
-                height,
-                img_type: if bit_depth == 8 {
-                    ImageType::Rgb8
-                } else {
-                    ImageType::Rgb16
-                },
-                data: unfiltered_data,
-            };
-            match trns_data {
-                TransparencyData::Truecolor(r, g, b) => {
-                    if bit_depth == 8 {
-                        img.data = img
-                            .data
-                            .chunks(3)
-                            .flat_map(|c| {
-                                let v = if c[0] as u16 == r && c[1] as u16 == g && c[2] as u16 == b
-                                {
-                                    0
-                                } else {
-                                    0xff
-                                };
-                                [c[0], c[1], c[2], v]
-                            })
-                            .collect();
-                        img.img_type = ImageType::Rgba8;
-                    } else {
-                        img.img_type = ImageType::Rgba16;
-
-                        img.data = img
-                            .data
-                            .chunks(6)
-                            .flat_map(|c| {
-                                let v = if to_u16(c[0], c[1]) == r
-                                    && to_u16(c[2], c[3]) == g
-                                    && to_u16(c[4], c[5]) == b
-                                {
-                                    0
-                                } else {
-                                    0xff
-                                };
-                                [c[0], c[1], c[2], c[3], c[4], c[5], v, v]
-                            })
-                            .collect();
-                    }
-                }
-                TransparencyData::None => {}
-                _ => unreachable!(),
+    ///Reads all 7 passes of an interlaced image, and combines them into the full image
+    fn read_interlaced(&mut self) -> Result<Vec<u8>, Error> {
+        let pixel_size = self.pixel_size();
+        let mut passes = Vec::new();
+
+        for pass in ADAM7_PASSES {
+            let (pass_width, pass_height) = pass_size(self.frame_width, self.frame_height, pass);
+
+            //Empty passes are not stored at all
+            if pass_width == 0 || pass_height == 0 {
+                continue;
+            }
+
+            self.start_pass();
+
+            let mut pixels = Vec::new();
+            for _ in 0..pass_height {
+                self.read_scanline(pass_width)?;
+                pixels.extend_from_slice(&self.output);
             }
-            img
+
+            passes.push((pass, pass_width, pixels));
         }
 
-        ColorType::TruecolorAlpha => Image {
-            width,
-            height,
-            img_type: if bit_depth == 8 {
-                ImageType::Rgba8
-            } else {
-                ImageType::Rgba16
-            },
-            data: unfiltered_data,
-        },
-        ColorType::IndexedColor => {
-            let indexes = match bit_depth {
-                1 | 2 | 4 => {
-                    let mut o = Vec::new();
-
-                    //Iterate over N bits (N = bit_depth)
-                    //Extract data i & 2^N - 1 << iter_num
-                    for i in &unfiltered_data {
-                        for index in 0..(8 / bit_depth) {
-                            let indexer: u8 = ((1 << bit_depth) - 1) << (index * bit_depth);
-                            let num = (i & indexer) >> (index * bit_depth);
-                            //This is a terrible hack, buuut like who's gonna be using 1 bit
-                            //indexed pngs in 2025!?!?
-                            //
-                            //or just indexed pngs in general, it's fiiiiine :3
-                            if bit_depth == 1 {
-                                o.push(num ^ 1);
-                            } else {
-                                o.push(num);
-                            }
-                        }
-                    }
-                    o
-                }
-                8 => unfiltered_data,
-                _ => return Err(Error::InvalidPngData("Invalid bit depth")),
-            };
+        let width = self.frame_width as usize;
+        let mut output = vec![0; width * self.frame_height as usize * pixel_size];
+
+        for ((x0, y0, dx, dy), pass_width, pixels) in passes {
+            for (index, pixel) in pixels.chunks(pixel_size).enumerate() {
+                let x = x0 + (index as u32 % pass_width) * dx;
+                let y = y0 + (index as u32 / pass_width) * dy;
+
+                let dst = (y as usize * width + x as usize) * pixel_size;
+                output[dst..dst + pixel_size].copy_from_slice(pixel);
+            }
+        }
 
-            match trns_data {
-                TransparencyData::Indexed(trns_pallete) => Image {
-                    width,
-                    height,
-                    img_type: ImageType::Rgba8,
-                    data: indexes
-                        .into_iter()
-                        .flat_map(|i| {
-                            let c = pallete.get(i);
-                            [c[0], c[1], c[2], trns_pallete.get(i)]
-                        })
-                        .collect(),
-                },
-                TransparencyData::None => Image {
-                    width,
-                    height,
-                    img_type: ImageType::Rgb8,
-                    data: indexes
-                        .into_iter()
-                        .flat_map(|i| pallete.get(i))
-                        .copied()
-                        .collect(),
-                },
-
-                _ => unreachable!(),
-            }
-        }
-        ColorType::GreyscaleAlpha => {
-            if bit_depth == 16 {
-                Image {
-                    width,
-                    height,
-                    img_type: ImageType::Ra16,
-                    data: unfiltered_data,
+        Ok(output)
+    }
+
+    ///Converts unpacked samples into the output format, 16 bit samples are converted to the
+    ///requested byte order
+    fn convert_row(&self, samples: &[u8], output: &mut Vec<u8>) -> Result<(), Error> {
+        if matches!(self.color_type, ColorType::IndexedColor) {
+            if self.keep_indices {
+                for i in samples {
+                    //Make sure the index is valid
+                    self.pallete.get(*i)?;
+                    output.push(*i);
                 }
-            } else {
-                Image {
-                    width,
-                    height,
-                    img_type: ImageType::Ra8,
-                    data: unfiltered_data,
+
+                return Ok(());
+            }
+
+            for i in samples {
+                output.extend_from_slice(self.pallete.get(*i)?);
+
+                if let TransparencyData::Indexed(trns_pallete) = &self.trns_data {
+                    output.push(trns_pallete.get(*i));
                 }
             }
+
+            return Ok(());
+        }
+
+        //Color that should be made transparent
+        let key = match self.trns_data {
+            TransparencyData::Greyscale(v) => Some([v, 0, 0]),
+            TransparencyData::Truecolor(r, g, b) => Some([r, g, b]),
+            _ => None,
+        };
+
+        let sample_size = if self.bit_depth == 16 { 2 } else { 1 };
+        let channels = self.color_type.channels() as usize;
+
+        let shifts = self.significant_shifts();
+
+        for pixel in samples.chunks_exact(channels * sample_size) {
+            let mut transparent = true;
+
+            for (channel, sample) in pixel.chunks_exact(sample_size).enumerate() {
+                let value = match self.bit_depth {
+                    16 => {
+                        let value = to_u16(sample[1], sample[0]);
+                        output.extend_from_slice(
+                            &self.options.byte_order.to_bytes(value >> shifts[channel]),
+                        );
+                        value
+                    }
+                    8 => {
+                        output.push(sample[0] >> shifts[channel]);
+                        sample[0] as u16
+                    }
+                    _ if self.options.preserve_bit_depth && key.is_none() => {
+                        output.push(sample[0]);
+                        sample[0] as u16
+                    }
+                    //Normalize over 0-255 (255 / ((2 << N) - 1) * num)
+                    _ => {
+                        output.push((255 / ((1 << self.bit_depth) - 1)) * sample[0]);
+                        sample[0] as u16
+                    }
+                };
+
+                transparent &= key.is_some_and(|k| k[channel] == value);
+            }
+
+            if key.is_some() {
+                let alpha = if transparent { 0 } else { 0xff };
+                output.extend(std::iter::repeat_n(alpha, sample_size));
+            }
+        }
+
+        Ok(())
+    }
+
+    ///Skips any remaining data of the current image
+    fn finish_image(&mut self) -> Result<(), Error> {
+        while !self.idat_done {
+            self.input_pos = self.input.len();
+            self.refill_input()?;
+        }
+
+        Ok(())
+    }
+
+    ///Skips any remaining image data and reads the chunks up to the end of the file
+    fn finish(&mut self) -> Result<(), Error> {
+        self.finish_image()?;
+
+        while !self.ended {
+            let (length, chunk_type) = read_chunk_header(&mut self.reader)?;
+            let data = read_chunk_data(&mut self.reader, chunk_type, length)?;
+
+            self.handle_chunk(chunk_type, data)?;
+        }
+
+        Ok(())
+    }
+}
+
+///Converts an sRGB encoded value to linear light
+fn srgb_to_linear(v: f32) -> f32 {
+    if v <= 0.040_45 {
+        v / 12.92
+    } else {
+        ((v + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+///Converts a linear light value to sRGB
+fn linear_to_srgb(v: f32) -> f32 {
+    if v <= 0.003_130_8 {
+        v * 12.92
+    } else {
+        1.055f32.mul_add(v.powf(1.0 / 2.4), -0.055)
+    }
+}
+
+///Decompresses zlib data stored in an ancillary chunk
+fn inflate(data: &[u8]) -> Result<Vec<u8>, Error> {
+    let mut output = Vec::new();
+
+    flate2::read::ZlibDecoder::new(data)
+        .read_to_end(&mut output)
+        .map_err(|_| Error::ZlibError)?;
+
+    Ok(output)
+}
+
+///Converts latin-1 text into a string
+fn latin1(data: &[u8]) -> String {
+    data.iter().copied().map(char::from).collect()
+}
+
+///Parses a tEXt, zTXt or iTXt chunk
+fn parse_text(chunk_type: [u8; 4], data: &[u8]) -> Result<TextChunk, Error> {
+    const INVALID: Error = Error::InvalidPngData("Invalid text chunk");
+
+    //Splits off a null terminated string
+    let split = |data: &[u8]| {
+        data.iter()
+            .position(|b| *b == 0)
+            .map(|i| (data[..i].to_vec(), data[i + 1..].to_vec()))
+            .ok_or(INVALID)
+    };
+
+    let (keyword, rest) = split(data)?;
+
+    if keyword.is_empty() || keyword.len() > 79 {
+        return Err(INVALID);
+    }
+
+    let keyword = latin1(&keyword);
+
+    match &chunk_type {
+        b"tEXt" => Ok(TextChunk {
+            keyword,
+            text: latin1(&rest),
+            compressed: false,
+            international: None,
+        }),
+        b"zTXt" => {
+            //Only compression method 0 (zlib) is defined
+            let [0, compressed @ ..] = &rest[..] else {
+                return Err(INVALID);
+            };
+
+            Ok(TextChunk {
+                keyword,
+                text: latin1(&inflate(compressed)?),
+                compressed: true,
+                international: None,
+            })
+        }
+        _ => {
+            let [compression_flag @ (0 | 1), 0, rest @ ..] = &rest[..] else {
+                return Err(INVALID);
+            };
+
+            let (language_tag, rest) = split(rest)?;
+            let (translated_keyword, text) = split(&rest)?;
+
+            let compressed = *compression_flag == 1;
+            let text = if compressed { inflate(&text)? } else { text };
+
+            let utf8 = |data: Vec<u8>| String::from_utf8(data).map_err(|_| INVALID);
+
+            Ok(TextChunk {
+                keyword,
+                text: utf8(text)?,
+                compressed,
+                international: Some(InternationalText {
+                    language_tag: utf8(language_tag)?,
+                    translated_keyword: utf8(translated_keyword)?,
+                }),
+            })
         }
+    }
+}
+
+///Parses an fcTL chunk, returning its sequence number and the frame control
+fn parse_frame_control(data: &[u8]) -> Result<(u32, FrameControl), Error> {
+    const INVALID: Error = Error::InvalidPngData("Invalid fcTL chunk");
+
+    if data.len() != 26 {
+        return Err(INVALID);
+    }
+
+    let u32_at = |i: usize| u32::from_be_bytes([data[i], data[i + 1], data[i + 2], data[i + 3]]);
+    let u16_at = |i: usize| u16::from_be_bytes([data[i], data[i + 1]]);
+
+    let control = FrameControl {
+        width: u32_at(4),
+        height: u32_at(8),
+        x_offset: u32_at(12),
+        y_offset: u32_at(16),
+        delay_num: u16_at(20),
+        delay_den: u16_at(22),
+        dispose_op: match data[24] {
+            0 => DisposeOp::None,
+            1 => DisposeOp::Background,
+            2 => DisposeOp::Previous,
+            _ => return Err(INVALID),
+        },
+        blend_op: match data[25] {
+            0 => BlendOp::Source,
+            1 => BlendOp::Over,
+            _ => return Err(INVALID),
+        },
     };
 
-    if bit_depth == 16 {
-        let data_inversed = img.data.chunks(2).flat_map(|i| [i[1], i[0]]).collect();
-        img.data = data_inversed;
+    if control.width == 0 || control.height == 0 {
+        return Err(INVALID);
+    }
+
+    Ok((u32_at(0), control))
+}
+
+///Reads the length and type of a chunk
+fn read_chunk_header(reader: &mut impl Read) -> Result<(u32, [u8; 4]), Error> {
+    let mut header = [0; 8];
+    reader.read_exact(&mut header)?;
+
+    let length = u32::from_be_bytes([header[0], header[1], header[2], header[3]]);
+    let chunk_type = [header[4], header[5], header[6], header[7]];
+
+    //Chunk length is limited to 2^31 - 1 bytes
+    if length > i32::MAX as u32 {
+        return Err(Error::InvalidPngData("Chunk length is too large"));
+    }
+
+    //Validate the type early
+    get_chunk_type(chunk_type)?;
+
+    Ok((length, chunk_type))
+}
+
+///Reads the data of a chunk and verifies its CRC
+fn read_chunk_data(
+    reader: &mut impl Read,
+    chunk_type: [u8; 4],
+    length: u32,
+) -> Result<Vec<u8>, Error> {
+    let mut data = Vec::new();
+    reader.take(length as u64).read_to_end(&mut data)?;
+
+    if data.len() != length as usize {
+        return Err(Error::TruncatedStream);
+    }
+
+    let mut crc = [0; 4];
+    reader.read_exact(&mut crc)?;
+
+    let computed_crc = update_crc(update_crc(u32::MAX, &chunk_type), &data) ^ u32::MAX;
+
+    if u32::from_be_bytes(crc) != computed_crc {
+        return Err(Error::InvalidCrc);
     }
 
-    Ok(img)
+    Ok(data)
+}
+
+///Parses a png image from a given stream
+///
+///# Errors
+///
+///May return an error if the data stream doesn't contain a valid png image
+///
+///
+///# Examples
+///
+///```no_run
+///# use std::io::Read;
+///# let mut file = std::fs::File::open("...").unwrap();
+///let mut data = Vec::new();
+///
+///file.read_to_end(&mut data);
+///
+///let image = lunar_png::decode_png(&mut data.into_iter()).unwrap();
+///```
+pub fn decode_png(stream: &mut impl Iterator<Item = u8>) -> Result<Image, Error> {
+    decode_png_with_metadata(stream).map(|(image, _)| image)
+}
+
+///Parses a png image from a given stream, also returning the metadata stored in the file
+///
+///# Errors
+///
+///May return an error if the data stream doesn't contain a valid png image
+pub fn decode_png_with_metadata(
+    stream: &mut impl Iterator<Item = u8>,
+) -> Result<(Image, Metadata), Error> {
+    Decoder::new(IterReader(stream))?.decode()
+}
+
+///Parses a png image from a given stream with the given settings, also returning the metadata
+///stored in the file
+///
+///# Errors
+///
+///May return an error if the data stream doesn't contain a valid png image
+pub fn decode_png_with_options(
+    stream: &mut impl Iterator<Item = u8>,
+    options: &DecodingOptions,
+) -> Result<(Image, Metadata), Error> {
+    Decoder::with_options(IterReader(stream), options)?.decode()
+}
+
+///Parses an indexed png from a given stream, keeping the palette indices instead of expanding
+///them into colors
+///
+///# Errors
+///
+///Returns [`Error::NotIndexed`] if the image doesn't use indexed color, may return an error if the
+///data stream doesn't contain a valid png image
+pub fn decode_indexed_png(stream: &mut impl Iterator<Item = u8>) -> Result<IndexedImage, Error> {
+    Decoder::new(IterReader(stream))?
+        .decode_indexed()
+        .map(|(image, _)| image)
+}
+
+///Parses an animated png from a given stream, returning every frame of the animation.
+///Images without animation information are returned as a single frame animation
+///
+///# Errors
+///
+///May return an error if the data stream doesn't contain a valid png image
+///
+///# Examples
+///
+///```no_run
+///# let data: Vec<u8> = Vec::new();
+///let animation = lunar_png::decode_apng(&mut data.into_iter()).unwrap();
+///
+///let canvases = animation.composite(lunar_png::ByteOrder::LittleEndian);
+///
+///for (frame, canvas) in animation.frames.iter().zip(canvases) {
+///    //Display the canvas for frame.control.delay()
+///}
+///```
+pub fn decode_apng(stream: &mut impl Iterator<Item = u8>) -> Result<Animation, Error> {
+    let mut decoder = Decoder::new(IterReader(stream))?;
+
+    let image = decoder.read_image()?;
+
+    let mut frames = Vec::new();
+    let mut default_image = None;
+
+    match (decoder.frame_control, decoder.animation_control) {
+        (Some(control), _) => frames.push(Frame { control, image }),
+        (None, Some(_)) => default_image = Some(image),
+        (None, None) => frames.push(Frame {
+            control: FrameControl {
+                width: decoder.width,
+                height: decoder.height,
+                x_offset: 0,
+                y_offset: 0,
+                delay_num: 0,
+                delay_den: 0,
+                dispose_op: DisposeOp::None,
+                blend_op: BlendOp::Source,
+            },
+            image,
+        }),
+    }
+
+    while let Some(control) = decoder.next_frame()? {
+        let image = decoder.read_image()?;
+        frames.push(Frame { control, image });
+    }
+
+    decoder.finish()?;
+
+    Ok(Animation {
+        width: decoder.width,
+        height: decoder.height,
+        num_plays: decoder.animation_control.map_or(0, |c| c.num_plays),
+        frames,
+        default_image,
+    })
 }
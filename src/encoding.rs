@@ -1,15 +1,18 @@
-use std::io::Write;
+use std::io::{Error, ErrorKind, Write};
 
 use crate::{
-    Image, ImageType,
-    helpers::{Filtered, compute_crc},
+    Animation, Background, BlendOp, ByteOrder, Chromaticities, CodingIndependentCodePoints,
+    DisposeOp, Frame, FrameControl, Gamma, IccProfile, Image, ImageType, IndexedImage,
+    MasteringDisplay, ModificationTime, Palette, PhysicalDimensions, PixelUnit, Quantization,
+    RenderingIntent, TextChunk,
+    helpers::{ADAM7_PASSES, compute_crc, paeth_predictor, pass_size},
+    quantize::quantize,
 };
-use chrono::{Datelike, Timelike};
-use flate2::Compression;
+use flate2::{Compression, write::ZlibEncoder};
 use pack1::{U16BE, U32BE};
 
 ///Compression level of the encoded image
-#[derive(Default, Debug, Copy, Clone)]
+#[derive(Default, Debug, Copy, Clone, PartialEq, Eq)]
 pub enum CompressionLevel {
     ///No compression at all, fastest
     None,
@@ -20,16 +23,89 @@ pub enum CompressionLevel {
     Best,
 }
 
+///How scanlines are filtered before being compressed
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum FilterStrategy {
+    ///Store the bytes unchanged
+    None,
+    ///Predict every byte from the byte to the left
+    Sub,
+    ///Predict every byte from the byte above
+    Up,
+    ///Predict every byte from the average of the bytes to the left and above
+    Average,
+    ///Predict every byte using the Paeth predictor
+    Paeth,
+    ///Pick the filter with the smallest sum of absolute differences for every scanline
+    MinSum,
+    ///Compress every scanline with every filter and keep the smallest, slowest
+    BruteForce,
+}
+
 ///Settings for png encoding
 //Compression options,
 //whether to write a timestamp
 //etc?
-#[derive(Default, Debug, Clone, Copy)]
+#[derive(Default, Debug, Clone)]
+#[allow(clippy::struct_excessive_bools)]
 pub struct PngEncodingOptions {
     ///How much to compress  the image
     pub compression: CompressionLevel,
-    ///Wether to write a time stamp to the image
+    ///Wether to write the current time to the image as the modification time. Ignored if
+    ///[`Self::modification_time`] is set, can't be combined with [`Self::deterministic`]
     pub write_timestamp: bool,
+    ///Modification time to write to the image, e.g. a fixed time for reproducible output
+    pub modification_time: Option<ModificationTime>,
+    ///How to filter the image data, `None` uses Paeth filtering if the image is compressed, and no
+    ///filtering otherwise
+    pub filter: Option<FilterStrategy>,
+    ///Byte order of the 16 bit samples of the image being encoded
+    pub byte_order: ByteOrder,
+    ///Whether to store the image using Adam7 interlacing, allowing it to be displayed progressively
+    pub interlaced: bool,
+    ///Text entries to store in the image
+    pub text: Vec<TextChunk>,
+    ///Image gamma to store in the image
+    pub gamma: Option<Gamma>,
+    ///Chromaticities to store in the image
+    pub chromaticities: Option<Chromaticities>,
+    ///Marks the image as using the sRGB color space with the given rendering intent. Can't be
+    ///combined with an ICC profile
+    pub srgb: Option<RenderingIntent>,
+    ///ICC profile to embed in the image. Can't be combined with sRGB
+    pub icc_profile: Option<IccProfile>,
+    ///Color space code points to store in the image, e.g. to mark it as HDR
+    pub cicp: Option<CodingIndependentCodePoints>,
+    ///Mastering display color volume to store in the image
+    pub mastering_display: Option<MasteringDisplay>,
+    ///Physical pixel dimensions to store in the image, e.g. the resolution of a scan
+    pub physical_dimensions: Option<PhysicalDimensions>,
+    ///Number of significant bits of every channel to store in the sBIT chunk, in the order of
+    ///the channels. Indexed images store the bits of the red, green and blue palette colors
+    pub significant_bits: Option<Vec<u8>>,
+    ///Whether the 8 and 16 bit samples passed to the encoder only use the significant bits, e.g.
+    ///0-1023 for 10 bit data. The samples are scaled to the full bit depth by left bit
+    ///replication, so the maximum value maps to the maximum of the bit depth. Has no effect on
+    ///indexed images or without [`Self::significant_bits`]
+    pub replicate_significant_bits: bool,
+    ///Raw EXIF data to store in the image, starting with a TIFF header
+    pub exif: Option<Vec<u8>>,
+    ///Background color to store in the bKGD chunk. Samples use the bit depth of the image,
+    ///indexed images store the palette entry with the same color
+    pub background: Option<Background>,
+    ///Rejects [`Self::write_timestamp`] without a [`Self::modification_time`], the only option
+    ///that depends on the environment. Nothing else changes, as the output is already byte
+    ///identical for identical images and options.
+    ///
+    ///Compression uses the zlib-rs backend unless another crate in the dependency graph enables
+    ///a different flate2 backend, so the output may change with the flate2 backend and version
+    pub deterministic: bool,
+    ///Whether to crop every animation frame to the area that changed since the previous frame.
+    ///Only used by [`encode_apng`], 16 bit frames can use any [`Self::byte_order`]
+    pub crop_frames: bool,
+    ///Reduces 8 bit images to a palette before encoding them, 16 bit images are stored unchanged.
+    ///Only used by [`encode_png`]
+    pub quantize: Option<Quantization>,
 }
 
 #[repr(C, packed)]
@@ -57,174 +133,1259 @@ struct Time {
     second: u8,
 }
 
-///Encodes a png into a byte stream
-#[must_use]
-#[allow(clippy::too_many_lines, clippy::missing_panics_doc)]
-pub fn encode_png(image: &Image, options: &PngEncodingOptions) -> Vec<u8> {
-    //Chunk support:
-    //IHDR
-    //IDAT
-    //IEND
-    //tIME
+///Layout of the image data
+#[derive(Clone, Copy)]
+struct Format {
+    color_type: u8,
+    bit_depth: u8,
+    //Size of a single pixel of the rows passed to the encoder
+    pixel_size: usize,
+}
+
+impl TryFrom<ImageType> for Format {
+    type Error = Error;
+
+    fn try_from(img_type: ImageType) -> Result<Self, Self::Error> {
+        let color_type = match img_type {
+            ImageType::R1 | ImageType::R2 | ImageType::R4 | ImageType::R8 | ImageType::R16 => 0,
+            ImageType::Ra8 | ImageType::Ra16 => 4,
+            ImageType::Rgb8 | ImageType::Rgb16 => 2,
+            ImageType::Rgba8 | ImageType::Rgba16 => 6,
+            ImageType::R32F | ImageType::Ra32F | ImageType::Rgb32F | ImageType::Rgba32F => {
+                return Err(Error::new(
+                    ErrorKind::InvalidInput,
+                    "floating point images can't be encoded",
+                ));
+            }
+        };
+
+        Ok(Self {
+            color_type,
+            bit_depth: img_type.bit_depth(),
+            pixel_size: img_type.bytes_per_pixel(),
+        })
+    }
+}
+
+///Maximum size of a single IDAT chunk
+const IDAT_SIZE: usize = 64 * 1024;
+
+///Writes a chunk, `data` must start with the chunk type
+fn write_chunk(writer: &mut impl Write, data: &[u8]) -> std::io::Result<()> {
+    let length: U32BE = (data.len() as u32 - 4).into();
+    let crc: U32BE = compute_crc(data).into();
+
+    writer.write_all(bytemuck::bytes_of(&length))?;
+    writer.write_all(data)?;
+    writer.write_all(bytemuck::bytes_of(&crc))
+}
+
+///Converts a string into latin-1, returns `None` if it contains characters outside of latin-1
+fn to_latin1(text: &str) -> Option<Vec<u8>> {
+    text.chars().map(|c| u8::try_from(c).ok()).collect()
+}
+
+fn compress(data: &[u8], profile: Compression) -> std::io::Result<Vec<u8>> {
+    let mut enc = ZlibEncoder::new(Vec::new(), profile);
+    enc.write_all(data)?;
+    enc.finish()
+}
+
+///Serializes a text entry, picking the chunk type based on its contents
+fn text_chunk(text: &TextChunk, profile: Compression) -> std::io::Result<Vec<u8>> {
+    let keyword = to_latin1(&text.keyword)
+        .filter(|k| (1..=79).contains(&k.len()) && !k.contains(&0))
+        .ok_or_else(|| Error::new(ErrorKind::InvalidInput, "invalid text keyword"))?;
+
+    let latin1 = to_latin1(&text.text);
+
+    let mut data = Vec::new();
+
+    match (&text.international, latin1) {
+        (None, Some(latin1)) if text.compressed => {
+            data.extend_from_slice(b"zTXt");
+            data.extend_from_slice(&keyword);
+            //Null separator and compression method
+            data.extend_from_slice(&[0, 0]);
+            data.append(&mut compress(&latin1, profile)?);
+        }
+        (None, Some(mut latin1)) => {
+            data.extend_from_slice(b"tEXt");
+            data.extend_from_slice(&keyword);
+            data.push(0);
+            data.append(&mut latin1);
+        }
+        (international, _) => {
+            let international = international.clone().unwrap_or_default();
+
+            //Both are null terminated
+            if international.language_tag.contains('\0')
+                || international.translated_keyword.contains('\0')
+            {
+                return Err(Error::new(
+                    ErrorKind::InvalidInput,
+                    "invalid international text",
+                ));
+            }
+
+            data.extend_from_slice(b"iTXt");
+            data.extend_from_slice(&keyword);
+            //Null separator, compression flag and compression method
+            data.extend_from_slice(&[0, u8::from(text.compressed), 0]);
+            data.extend_from_slice(international.language_tag.as_bytes());
+            data.push(0);
+            data.extend_from_slice(international.translated_keyword.as_bytes());
+            data.push(0);
+
+            if text.compressed {
+                data.append(&mut compress(text.text.as_bytes(), profile)?);
+            } else {
+                data.extend_from_slice(text.text.as_bytes());
+            }
+        }
+    }
+
+    Ok(data)
+}
+
+///Splits the compressed image data into IDAT chunks of at most [`IDAT_SIZE`] bytes, or fdAT
+///chunks if a sequence number is given
+struct IdatWriter<W: Write> {
+    writer: W,
+    //Chunk type followed by the data of the current chunk
+    buffer: Vec<u8>,
+    //Whether fdAT chunks are written instead of IDAT chunks
+    fdat: bool,
+    //Sequence number of the next fdAT chunk
+    sequence: u32,
+}
+
+impl<W: Write> IdatWriter<W> {
+    fn new(writer: W, sequence: Option<u32>) -> Self {
+        let mut buffer = Vec::with_capacity(IDAT_SIZE + 8);
+        buffer.extend_from_slice(if sequence.is_some() { b"fdAT" } else { b"IDAT" });
+
+        let mut writer = Self {
+            writer,
+            buffer,
+            fdat: sequence.is_some(),
+            sequence: sequence.unwrap_or(0),
+        };
+        writer.start_chunk();
+
+        writer
+    }
+
+    ///Length of the chunk type and sequence number stored at the start of the buffer
+    const fn header_len(&self) -> usize {
+        if self.fdat { 8 } else { 4 }
+    }
+
+    fn start_chunk(&mut self) {
+        self.buffer.truncate(4);
+
+        if self.fdat {
+            self.buffer.extend_from_slice(&self.sequence.to_be_bytes());
+        }
+    }
+
+    ///Writes out the current chunk, if it contains any data
+    fn write_idat(&mut self) -> std::io::Result<()> {
+        if self.buffer.len() > self.header_len() {
+            write_chunk(&mut self.writer, &self.buffer)?;
+
+            self.sequence += 1;
+            self.start_chunk();
+        }
+
+        Ok(())
+    }
+}
+
+impl<W: Write> Write for IdatWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let max_len = IDAT_SIZE + self.header_len();
 
-    let mut stream = Vec::new();
+        let len = buf.len().min(max_len - self.buffer.len());
+        self.buffer.extend_from_slice(&buf[..len]);
+
+        if self.buffer.len() == max_len {
+            self.write_idat()?;
+        }
+
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+///Returns the default filter strategy, and the zlib compression level to use
+const fn compression_profile(compression: CompressionLevel) -> (FilterStrategy, Compression) {
+    match compression {
+        CompressionLevel::None => (FilterStrategy::None, Compression::none()),
+        CompressionLevel::Fast => (FilterStrategy::Paeth, Compression::fast()),
+        CompressionLevel::Best => (FilterStrategy::Paeth, Compression::best()),
+    }
+}
+
+///Writes the signature and every chunk preceding the image data
+#[allow(clippy::too_many_lines)]
+fn write_header(
+    writer: &mut impl Write,
+    width: u32,
+    height: u32,
+    format: Format,
+    palette: Option<&Palette>,
+    options: &PngEncodingOptions,
+) -> std::io::Result<()> {
+    if width == 0 || height == 0 {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            "image dimensions can not be 0",
+        ));
+    }
 
     let signature = [0x89, 0x50, 0x4E, 0x47, 0x0D, 0x0A, 0x1A, 0x0A];
-    stream.extend_from_slice(&signature);
+    writer.write_all(&signature)?;
 
     let header = Header {
         //IHDR
         signature: [0x49, 0x48, 0x44, 0x52],
-        width: image.width.into(),
-        height: image.height.into(),
-        bit_depth: match image.img_type {
-            ImageType::R8 | ImageType::Ra8 | ImageType::Rgb8 | ImageType::Rgba8 => 8,
-            _ => 16,
-        },
-        color_type: match image.img_type {
-            ImageType::R8 | ImageType::R16 => 0,
-            ImageType::Ra8 | ImageType::Ra16 => 4,
-            ImageType::Rgb8 | ImageType::Rgb16 => 2,
-            ImageType::Rgba8 | ImageType::Rgba16 => 6,
-        },
+        width: width.into(),
+        height: height.into(),
+        bit_depth: format.bit_depth,
+        color_type: format.color_type,
         compression_method: 0,
         filter_method: 0,
-        interlace_method: 0,
+        interlace_method: u8::from(options.interlaced),
     };
 
-    let bytes = bytemuck::bytes_of(&header);
-    let length: U32BE = (size_of::<Header>() as u32 - 4).into();
-    let crc: U32BE = compute_crc(bytes).into();
+    write_chunk(writer, bytemuck::bytes_of(&header))?;
 
-    stream.extend_from_slice(bytemuck::bytes_of(&length));
-    stream.extend_from_slice(bytes);
-    stream.extend_from_slice(bytemuck::bytes_of(&crc));
+    if options.deterministic && options.write_timestamp && options.modification_time.is_none() {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            "the current time can't be written to deterministic output",
+        ));
+    }
 
-    if options.write_timestamp {
-        let time = chrono::Utc::now();
+    let time = options
+        .modification_time
+        .or_else(|| options.write_timestamp.then(ModificationTime::now));
+
+    if let Some(time) = time {
+        if !time.is_valid() {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "invalid modification time",
+            ));
+        }
 
         let time = Time {
             signature: [0x74, 0x49, 0x4D, 0x45],
-            year: (time.year() as u16).into(),
-            month: time.month() as u8,
-            day: time.month() as u8,
-            hour: time.hour() as u8,
-            minute: time.minute() as u8,
-            second: time.second() as u8,
+            year: time.year.into(),
+            month: time.month,
+            day: time.day,
+            hour: time.hour,
+            minute: time.minute,
+            second: time.second,
         };
 
-        let bytes = bytemuck::bytes_of(&time);
-        let length: U32BE = (size_of::<Time>() as u32 - 4).into();
-        let crc: U32BE = compute_crc(bytes).into();
+        write_chunk(writer, bytemuck::bytes_of(&time))?;
+    }
+
+    let (_, profile) = compression_profile(options.compression);
 
-        stream.extend_from_slice(bytemuck::bytes_of(&length));
-        stream.extend_from_slice(bytes);
-        stream.extend_from_slice(bytemuck::bytes_of(&crc));
+    //Color space information
+    if options.srgb.is_some() && options.icc_profile.is_some() {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            "sRGB and an ICC profile can't be stored together",
+        ));
     }
 
-    //Written all the non data stuff
+    if let Some(gamma) = options.gamma {
+        let mut gama = b"gAMA".to_vec();
+        gama.extend_from_slice(&gamma.0.to_be_bytes());
+        write_chunk(writer, &gama)?;
+    }
 
-    let (filter, profile) = match options.compression {
-        CompressionLevel::None => (false, Compression::none()),
-        CompressionLevel::Fast => (true, Compression::fast()),
-        CompressionLevel::Best => (true, Compression::best()),
-    };
+    if let Some(c) = options.chromaticities {
+        let mut chrm = b"cHRM".to_vec();
+
+        for (x, y) in [c.white_point, c.red, c.green, c.blue] {
+            chrm.extend_from_slice(&x.to_be_bytes());
+            chrm.extend_from_slice(&y.to_be_bytes());
+        }
+
+        write_chunk(writer, &chrm)?;
+    }
+
+    if let Some(intent) = options.srgb {
+        let intent = match intent {
+            RenderingIntent::Perceptual => 0,
+            RenderingIntent::RelativeColorimetric => 1,
+            RenderingIntent::Saturation => 2,
+            RenderingIntent::AbsoluteColorimetric => 3,
+        };
+
+        write_chunk(writer, &[b's', b'R', b'G', b'B', intent])?;
+    }
+
+    if let Some(icc_profile) = &options.icc_profile {
+        let name = to_latin1(&icc_profile.name)
+            .filter(|k| (1..=79).contains(&k.len()) && !k.contains(&0))
+            .ok_or_else(|| Error::new(ErrorKind::InvalidInput, "invalid ICC profile name"))?;
+
+        let mut iccp = b"iCCP".to_vec();
+        iccp.extend_from_slice(&name);
+        //Null separator and compression method
+        iccp.extend_from_slice(&[0, 0]);
+        iccp.append(&mut compress(&icc_profile.data, profile)?);
+
+        write_chunk(writer, &iccp)?;
+    }
+
+    if let Some(cicp) = options.cicp {
+        if cicp.matrix_coefficients != 0 {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "cICP matrix coefficients have to be 0",
+            ));
+        }
 
-    //Allocate enough space for the entire image plus the filter markers
-    let mut image_data = Vec::with_capacity(image.data.len() + image.height as usize);
-
-    let scanline_size = image.width
-        * match image.img_type {
-            ImageType::R8 => 1,
-            ImageType::R16 | ImageType::Ra8 => 2,
-            ImageType::Rgb8 => 3,
-            ImageType::Ra16 | ImageType::Rgba8 => 4,
-            ImageType::Rgb16 => 6,
-            ImageType::Rgba16 => 8,
+        write_chunk(
+            writer,
+            &[
+                b'c',
+                b'I',
+                b'C',
+                b'P',
+                cicp.color_primaries,
+                cicp.transfer_characteristics,
+                cicp.matrix_coefficients,
+                cicp.full_range as u8,
+            ],
+        )?;
+    }
+
+    if let Some(display) = options.mastering_display {
+        let mut mdcv = b"mDCv".to_vec();
+
+        for (x, y) in display.primaries.into_iter().chain([display.white_point]) {
+            mdcv.extend_from_slice(&x.to_be_bytes());
+            mdcv.extend_from_slice(&y.to_be_bytes());
+        }
+
+        mdcv.extend_from_slice(&display.max_luminance.to_be_bytes());
+        mdcv.extend_from_slice(&display.min_luminance.to_be_bytes());
+
+        write_chunk(writer, &mdcv)?;
+    }
+
+    if let Some(bits) = &options.significant_bits {
+        let (channels, max) = match format.color_type {
+            0 => (1, format.bit_depth),
+            2 => (3, format.bit_depth),
+            3 => (3, 8),
+            4 => (2, format.bit_depth),
+            _ => (4, format.bit_depth),
         };
 
-    let is_16 = image.img_type.is_16_bit();
+        if bits.len() != channels || bits.iter().any(|b| !(1..=max).contains(b)) {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "invalid number of significant bits",
+            ));
+        }
+
+        let mut sbit = b"sBIT".to_vec();
+        sbit.extend_from_slice(bits);
+        write_chunk(writer, &sbit)?;
+    }
+
+    if let Some(exif) = &options.exif {
+        if !(exif.starts_with(b"II*\0") || exif.starts_with(b"MM\0*")) {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "EXIF data has to start with a TIFF header",
+            ));
+        }
+
+        let mut exif_chunk = b"eXIf".to_vec();
+        exif_chunk.extend_from_slice(exif);
+        write_chunk(writer, &exif_chunk)?;
+    }
+
+    if let Some(dimensions) = options.physical_dimensions {
+        let mut phys = b"pHYs".to_vec();
+        phys.extend_from_slice(&dimensions.x.to_be_bytes());
+        phys.extend_from_slice(&dimensions.y.to_be_bytes());
+        phys.push(match dimensions.unit {
+            PixelUnit::Unknown => 0,
+            PixelUnit::Meter => 1,
+        });
+
+        write_chunk(writer, &phys)?;
+    }
+
+    for text in &options.text {
+        write_chunk(writer, &text_chunk(text, profile)?)?;
+    }
+
+    if let Some(palette) = palette {
+        let mut plte = b"PLTE".to_vec();
+        plte.extend(palette.colors.iter().flatten());
+        write_chunk(writer, &plte)?;
 
-    if filter {
-        let filtetered = Filtered {
-            data: if image.img_type.is_16_bit() {
-                image.data.chunks(2).flat_map(|i| [i[1], i[0]]).collect()
+        if !palette.alpha.is_empty() {
+            let mut trns = b"tRNS".to_vec();
+            trns.extend_from_slice(&palette.alpha);
+            write_chunk(writer, &trns)?;
+        }
+    }
+
+    if let Some(background) = options.background {
+        let invalid = || Error::new(ErrorKind::InvalidInput, "invalid background color");
+
+        let color = match background {
+            Background::Gray(value) => [value; 3],
+            Background::Rgb(color) => color,
+        };
+
+        let mut bkgd = b"bKGD".to_vec();
+
+        if let Some(palette) = palette {
+            let index = palette
+                .colors
+                .iter()
+                .position(|c| c.map(u16::from) == color)
+                .ok_or_else(invalid)?;
+
+            bkgd.push(index as u8);
+        } else {
+            let samples = if format.color_type & 2 == 0 {
+                //Grayscale images need a gray background
+                if color[1] != color[0] || color[2] != color[0] {
+                    return Err(invalid());
+                }
+
+                &color[..1]
             } else {
-                image.data.clone()
-            },
-            color_type: match image.img_type {
-                ImageType::R8 | ImageType::R16 => crate::helpers::ColorType::Greyscale,
-                ImageType::Ra8 | ImageType::Ra16 => crate::helpers::ColorType::GreyscaleAlpha,
-                ImageType::Rgb8 | ImageType::Rgb16 => crate::helpers::ColorType::Truecolor,
-                ImageType::Rgba8 | ImageType::Rgba16 => crate::helpers::ColorType::TruecolorAlpha,
-            },
-            scanline_len: scanline_size,
-            bit_depth: match image.img_type {
-                ImageType::R8 | ImageType::Ra8 | ImageType::Rgb8 | ImageType::Rgba8 => 8,
-                _ => 16,
-            },
-            ignore_0: false,
+                &color[..]
+            };
+
+            if samples
+                .iter()
+                .any(|s| u32::from(*s) >> format.bit_depth != 0)
+            {
+                return Err(invalid());
+            }
+
+            for sample in samples {
+                bkgd.extend_from_slice(&sample.to_be_bytes());
+            }
+        }
+
+        write_chunk(writer, &bkgd)?;
+    }
+
+    //Written all the non data stuff
+
+    Ok(())
+}
+
+///A streaming png encoder.
+///
+///The header is written as soon as the encoder is created, and rows are filtered and compressed
+///as they are written, so the whole image never has to be in memory. Interlaced images are an
+///exception, as the passes can only be produced once every row is known.
+///
+///# Examples
+///
+///```no_run
+///# use lunar_png::*;
+///let file = std::fs::File::create("...").unwrap();
+///let mut encoder = Encoder::new(
+///    std::io::BufWriter::new(file),
+///    1024,
+///    1024,
+///    ImageType::Rgb8,
+///    &PngEncodingOptions::default(),
+///)
+///.unwrap();
+///
+///for _ in 0..1024 {
+///    encoder.write_row(&[0; 1024 * 3]).unwrap();
+///}
+///
+///encoder.finish().unwrap();
+///```
+pub struct Encoder<W: Write> {
+    stream: ZlibEncoder<IdatWriter<W>>,
+    width: u32,
+    height: u32,
+    format: Format,
+    //Upper bound of the sample values, only set for indexed images and bit depths below 8
+    sample_limit: Option<usize>,
+    filter: FilterStrategy,
+    profile: Compression,
+    byte_order: ByteOrder,
+    interlaced: bool,
+    //Significant bits of every channel, only set if the samples have to be replicated
+    significant_bits: Option<Vec<u8>>,
+    //Number of rows written so far
+    row: u32,
+    //Previous scanline in big endian, before filtering
+    previous: Vec<u8>,
+    //Filtered scanline, including the filter type byte
+    filtered: Vec<u8>,
+    //Whole image, only used for interlaced images
+    buffer: Vec<u8>,
+}
+
+impl<W: Write> Encoder<W> {
+    ///Creates a new encoder, writing the png header to the writer
+    ///
+    ///# Errors
+    ///
+    ///Returns an error if either dimension is 0, the image type is floating point, a text keyword
+    ///is invalid, or if writing fails
+    pub fn new(
+        mut writer: W,
+        width: u32,
+        height: u32,
+        img_type: ImageType,
+        options: &PngEncodingOptions,
+    ) -> std::io::Result<Self> {
+        let format = Format::try_from(img_type)?;
+        write_header(&mut writer, width, height, format, None, options)?;
+
+        Ok(Self::image_data(
+            writer, width, height, format, options, None,
+        ))
+    }
+
+    ///Creates a new encoder for an indexed image, writing the png header and the palette to the
+    ///writer. Rows contain one palette index per pixel, which are stored with the given bit depth
+    ///
+    ///# Errors
+    ///
+    ///Returns an error if either dimension is 0, the bit depth is not 1, 2, 4 or 8, the palette
+    ///is empty or has more entries than the bit depth can address, a text keyword is invalid, or
+    ///if writing fails
+    pub fn new_indexed(
+        mut writer: W,
+        width: u32,
+        height: u32,
+        bit_depth: u8,
+        palette: &Palette,
+        options: &PngEncodingOptions,
+    ) -> std::io::Result<Self> {
+        if ![1, 2, 4, 8].contains(&bit_depth) {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "invalid bit depth for an indexed image",
+            ));
+        }
+
+        if palette.colors.is_empty()
+            || palette.colors.len() > 1 << bit_depth
+            || palette.alpha.len() > palette.colors.len()
+        {
+            return Err(Error::new(ErrorKind::InvalidInput, "invalid palette size"));
+        }
+
+        let format = Format {
+            color_type: 3,
+            bit_depth,
+            pixel_size: 1,
         };
 
-        if is_16 {
-            let data = image.data.chunks(2).flat_map(|i| [i[1], i[0]]).enumerate();
+        write_header(&mut writer, width, height, format, Some(palette), options)?;
+
+        let mut encoder = Self::image_data(writer, width, height, format, options, None);
+        encoder.sample_limit = Some(palette.colors.len());
+
+        Ok(encoder)
+    }
+
+    ///Creates an encoder that only writes the image data, as fdAT chunks if a sequence number is
+    ///given
+    fn image_data(
+        writer: W,
+        width: u32,
+        height: u32,
+        format: Format,
+        options: &PngEncodingOptions,
+        sequence: Option<u32>,
+    ) -> Self {
+        let (filter, profile) = compression_profile(options.compression);
+        let filter = options.filter.unwrap_or(filter);
+
+        Self {
+            stream: ZlibEncoder::new(IdatWriter::new(writer, sequence), profile),
+            width,
+            height,
+            format,
+            //Palette sizes are set by the caller
+            sample_limit: (format.bit_depth < 8).then_some(1 << format.bit_depth),
+            filter,
+            profile,
+            byte_order: options.byte_order,
+            interlaced: options.interlaced,
+            significant_bits: options.significant_bits.clone().filter(|_| {
+                options.replicate_significant_bits
+                    && format.bit_depth >= 8
+                    && format.color_type != 3
+            }),
+            row: 0,
+            previous: Vec::new(),
+            filtered: Vec::new(),
+            buffer: Vec::new(),
+        }
+    }
+
+    ///Writes a single row of the image, in the same format as [`Image::data`]
+    ///
+    ///# Errors
+    ///
+    ///Returns an error if the row has the wrong length, every row has already been written, or
+    ///if writing fails
+    pub fn write_row(&mut self, row: &[u8]) -> std::io::Result<()> {
+        if row.len() != self.width as usize * self.format.pixel_size {
+            return Err(Error::new(ErrorKind::InvalidInput, "invalid row length"));
+        }
+
+        if let Some(limit) = self.sample_limit
+            && row.iter().any(|i| *i as usize >= limit)
+        {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "sample value out of range",
+            ));
+        }
+
+        if let Some(bits) = &self.significant_bits
+            && self
+                .read_samples(row)
+                .enumerate()
+                .any(|(i, v)| u32::from(v) >> bits[i % bits.len()] != 0)
+        {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "sample value exceeds the significant bits",
+            ));
+        }
+
+        if self.row == self.height {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "every row has already been written",
+            ));
+        }
+
+        self.row += 1;
+
+        if self.interlaced {
+            self.buffer.extend_from_slice(row);
+            return Ok(());
+        }
+
+        if self.row == 1 {
+            self.previous.clear();
+        }
+
+        self.write_scanline(row)
+    }
+
+    ///Writes multiple consecutive rows of the image
+    ///
+    ///# Errors
+    ///
+    ///Returns an error if the data is not made up of whole rows, there are more rows than the
+    ///image has left, or if writing fails
+    pub fn write_rows(&mut self, rows: &[u8]) -> std::io::Result<()> {
+        let row_size = self.width as usize * self.format.pixel_size;
+
+        if !rows.len().is_multiple_of(row_size) {
+            return Err(Error::new(ErrorKind::InvalidInput, "invalid row length"));
+        }
+
+        for row in rows.chunks(row_size) {
+            self.write_row(row)?;
+        }
+
+        Ok(())
+    }
+
+    ///Writes the remaining image data and the end of the png, returning the writer
+    ///
+    ///# Errors
+    ///
+    ///Returns an error if not every row of the image was written, or if writing fails
+    pub fn finish(self) -> std::io::Result<W> {
+        let (mut writer, _) = self.finish_data()?;
+
+        //end
+        write_chunk(&mut writer, &[0x49u8, 0x45, 0x4e, 0x44])?;
+        writer.flush()?;
+
+        Ok(writer)
+    }
+
+    ///Writes the remaining image data, returning the writer and the next fdAT sequence number
+    fn finish_data(mut self) -> std::io::Result<(W, u32)> {
+        if self.row != self.height {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "not every row of the image was written",
+            ));
+        }
+
+        if self.interlaced {
+            self.write_passes()?;
+        }
+
+        let mut idat = self.stream.finish()?;
+        idat.write_idat()?;
+
+        Ok((idat.writer, idat.sequence))
+    }
+
+    ///Splits the buffered image into the 7 Adam7 passes and writes them
+    fn write_passes(&mut self) -> std::io::Result<()> {
+        let pixel_size = self.format.pixel_size;
+        let buffer = std::mem::take(&mut self.buffer);
+
+        let mut row = Vec::new();
+
+        for pass in ADAM7_PASSES {
+            let (pass_width, pass_height) = pass_size(self.width, self.height, pass);
+
+            //Empty passes are not stored at all
+            if pass_width == 0 || pass_height == 0 {
+                continue;
+            }
+
+            let (x0, y0, dx, dy) = pass;
+
+            self.previous.clear();
+
+            //Collect the pixels of every scanline of the pass
+            for y in (y0..self.height).step_by(dy as usize) {
+                row.clear();
 
-            for (ind, d) in data {
-                if (ind as u32).is_multiple_of(scanline_size) {
-                    image_data.push(4);
+                for x in (x0..self.width).step_by(dx as usize) {
+                    let index = (y as usize * self.width as usize + x as usize) * pixel_size;
+                    row.extend_from_slice(&buffer[index..index + pixel_size]);
                 }
-                let pt = filtetered.paeth(ind);
-                image_data.push(d.wrapping_sub(pt));
+
+                self.write_scanline(&row)?;
             }
+        }
+
+        Ok(())
+    }
+
+    ///Reads the samples of an 8 or 16 bit row
+    fn read_samples<'a>(&self, row: &'a [u8]) -> impl Iterator<Item = u16> + 'a {
+        let byte_order = self.byte_order;
+        let is_16_bit = self.format.bit_depth == 16;
+
+        row.chunks(if is_16_bit { 2 } else { 1 }).map(move |s| {
+            if is_16_bit {
+                byte_order.read([s[0], s[1]])
+            } else {
+                s[0] as u16
+            }
+        })
+    }
+
+    ///Filters and compresses a single scanline
+    fn write_scanline(&mut self, row: &[u8]) -> std::io::Result<()> {
+        let row = match (self.format.bit_depth, &self.significant_bits) {
+            //Png stores 16 bit samples in big endian
+            (16, None) => self.read_samples(row).flat_map(u16::to_be_bytes).collect(),
+            (8, None) => row.to_vec(),
+            (16 | 8, Some(bits)) => {
+                let bit_depth = self.format.bit_depth;
+                let samples = self
+                    .read_samples(row)
+                    .enumerate()
+                    .map(|(i, v)| replicate_bits(v, bits[i % bits.len()], bit_depth));
+
+                if bit_depth == 16 {
+                    samples.flat_map(u16::to_be_bytes).collect()
+                } else {
+                    samples.map(|v| v as u8).collect()
+                }
+            }
+            (bit_depth, _) => pack_row(row, bit_depth),
+        };
+
+        //The first scanline of a pass is filtered against zeros
+        self.previous.resize(row.len(), 0);
+
+        let bpp = if self.format.bit_depth < 8 {
+            1
+        } else {
+            self.format.pixel_size
+        };
+
+        self.filtered.clear();
+
+        let filter_type = match self.filter {
+            FilterStrategy::None => Some(0),
+            FilterStrategy::Sub => Some(1),
+            FilterStrategy::Up => Some(2),
+            FilterStrategy::Average => Some(3),
+            FilterStrategy::Paeth => Some(4),
+            FilterStrategy::MinSum | FilterStrategy::BruteForce => None,
+        };
+
+        if let Some(filter_type) = filter_type {
+            filter_row(filter_type, &row, &self.previous, bpp, &mut self.filtered);
         } else {
-            //Paeth filtering
-            for (ind, d) in image.data.iter().enumerate() {
-                if (ind as u32).is_multiple_of(scanline_size) {
-                    image_data.push(4);
+            //Adaptive strategies try every filter and keep the best one
+            let mut best = usize::MAX;
+            let mut candidate = Vec::with_capacity(row.len() + 1);
+
+            for filter_type in 0..5 {
+                candidate.clear();
+                filter_row(filter_type, &row, &self.previous, bpp, &mut candidate);
+
+                let size = if self.filter == FilterStrategy::MinSum {
+                    //Bytes are treated as signed, so small negative differences count as small
+                    candidate[1..]
+                        .iter()
+                        .map(|b| b.cast_signed().unsigned_abs() as usize)
+                        .sum()
+                } else {
+                    compress(&candidate, self.profile)?.len()
+                };
+
+                if size < best {
+                    best = size;
+                    std::mem::swap(&mut self.filtered, &mut candidate);
                 }
-                let pt = filtetered.paeth(ind);
-                image_data.push(d.wrapping_sub(pt));
             }
         }
-    } else if is_16 {
-        for (ind, d) in image.data.chunks(2).enumerate() {
-            if (ind as u32).is_multiple_of(scanline_size / 2) {
-                image_data.push(0);
+
+        self.previous = row;
+
+        self.stream.write_all(&self.filtered)
+    }
+}
+
+///Filters a scanline, appending the filter type and the filtered bytes to `output`. `previous` is
+///the unfiltered previous scanline
+fn filter_row(filter_type: u8, row: &[u8], previous: &[u8], bpp: usize, output: &mut Vec<u8>) {
+    output.push(filter_type);
+
+    for (i, x) in row.iter().enumerate() {
+        //Bytes
+        //  |c|b|
+        //  |a|x|
+        //  x = current
+        let (a, c) = if i >= bpp {
+            (row[i - bpp], previous[i - bpp])
+        } else {
+            (0, 0)
+        };
+        let b = previous[i];
+
+        let prediction = match filter_type {
+            1 => a,
+            2 => b,
+            3 => a.midpoint(b),
+            4 => paeth_predictor(a, b, c),
+            _ => 0,
+        };
+
+        output.push(x.wrapping_sub(prediction));
+    }
+}
+
+///Packs samples smaller than a byte into a scanline, the first sample is stored in the most
+///significant bits
+fn pack_row(samples: &[u8], bit_depth: u8) -> Vec<u8> {
+    let per_byte = 8 / bit_depth as usize;
+
+    samples
+        .chunks(per_byte)
+        .map(|chunk| {
+            chunk.iter().enumerate().fold(0, |byte, (i, sample)| {
+                byte | sample << (8 - bit_depth as usize * (i + 1))
+            })
+        })
+        .collect()
+}
+
+///Encodes a png into a byte stream
+///
+///# Panics
+///
+///Panics if [`try_encode_png`] returns an error
+#[must_use]
+pub fn encode_png(image: &Image, options: &PngEncodingOptions) -> Vec<u8> {
+    try_encode_png(image, options).expect("Invalid image or encoding options")
+}
+
+///Encodes a png into a byte stream
+///
+///# Errors
+///
+///Returns an error if the image is floating point or either dimension is 0, if the length of the
+///image data doesn't match its dimensions and type, if a sample exceeds the significant bits, or
+///if the options contain invalid metadata: text keywords, language tags or ICC profile names, both
+///sRGB and an ICC profile, a modification time out of range or the current time in deterministic
+///output, cICP matrix coefficients other than 0, an invalid number of significant bits, EXIF data
+///without a TIFF header or a background color that doesn't fit the image
+pub fn try_encode_png(image: &Image, options: &PngEncodingOptions) -> std::io::Result<Vec<u8>> {
+    //Chunk support:
+    //IHDR
+    //tIME
+    //gAMA
+    //cHRM
+    //sRGB
+    //iCCP
+    //cICP
+    //mDCv
+    //sBIT
+    //eXIf
+    //pHYs
+    //tEXt
+    //zTXt
+    //iTXt
+    //PLTE
+    //tRNS
+    //bKGD
+    //IDAT
+    //IEND
+
+    //Quantization indexes the data by the dimensions before the encoder can check it
+    if image.data.len()
+        != image.width as usize * image.height as usize * image.img_type.bytes_per_pixel()
+    {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            "invalid image data length",
+        ));
+    }
+
+    if let Some(quantization) = &options.quantize
+        && image.img_type.bit_depth() <= 8
+    {
+        return try_encode_indexed_png(&quantize(image, quantization), options);
+    }
+
+    let mut encoder = Encoder::new(
+        Vec::new(),
+        image.width,
+        image.height,
+        image.img_type,
+        options,
+    )?;
+
+    encoder.write_rows(&image.data)?;
+    encoder.finish()
+}
+
+///Scales a sample with the given number of significant bits to the bit depth, by repeating its
+///bits
+const fn replicate_bits(value: u16, bits: u8, bit_depth: u8) -> u16 {
+    let mut output = 0u32;
+    let mut filled = 0;
+
+    while filled < bit_depth {
+        output = (output << bits) | value as u32;
+        filled += bits;
+    }
+
+    (output >> (filled - bit_depth)) as u16
+}
+
+///Serializes a frame control chunk
+fn frame_control(sequence: u32, control: &FrameControl) -> Vec<u8> {
+    let mut data = Vec::with_capacity(30);
+    data.extend_from_slice(b"fcTL");
+
+    for value in [
+        sequence,
+        control.width,
+        control.height,
+        control.x_offset,
+        control.y_offset,
+    ] {
+        data.extend_from_slice(&value.to_be_bytes());
+    }
+
+    data.extend_from_slice(&control.delay_num.to_be_bytes());
+    data.extend_from_slice(&control.delay_den.to_be_bytes());
+
+    data.push(match control.dispose_op {
+        DisposeOp::None => 0,
+        DisposeOp::Background => 1,
+        DisposeOp::Previous => 2,
+    });
+    data.push(match control.blend_op {
+        BlendOp::Source => 0,
+        BlendOp::Over => 1,
+    });
+
+    data
+}
+
+///Replaces every frame with the area of the composited canvas that changed since the previous
+///frame
+fn crop_frames(animation: &Animation, byte_order: ByteOrder) -> Vec<Frame> {
+    let canvases = animation.composite(byte_order);
+    let pixel_size = canvases[0].img_type.bytes_per_pixel();
+    let width = animation.width as usize;
+
+    let mut frames = Vec::with_capacity(canvases.len());
+
+    for (index, canvas) in canvases.iter().enumerate() {
+        let control = animation.frames[index].control;
+
+        //The first frame always covers the whole canvas
+        let (mut x0, mut y0, mut x1, mut y1) = if index == 0 {
+            (0, 0, animation.width, animation.height)
+        } else {
+            (u32::MAX, u32::MAX, 0, 0)
+        };
+
+        if index != 0 {
+            let previous = &canvases[index - 1];
+
+            for (i, (a, b)) in canvas
+                .data
+                .chunks(pixel_size)
+                .zip(previous.data.chunks(pixel_size))
+                .enumerate()
+            {
+                if a != b {
+                    let (x, y) = ((i % width) as u32, (i / width) as u32);
+                    x0 = x0.min(x);
+                    y0 = y0.min(y);
+                    x1 = x1.max(x + 1);
+                    y1 = y1.max(y + 1);
+                }
             }
 
-            image_data.push(d[1]);
-            image_data.push(d[0]);
+            //Nothing changed, a frame has to contain at least one pixel
+            if x1 == 0 {
+                (x0, y0, x1, y1) = (0, 0, 1, 1);
+            }
+        }
+
+        let mut data = Vec::new();
+        for y in y0..y1 {
+            let start = (y as usize * width + x0 as usize) * pixel_size;
+            data.extend_from_slice(&canvas.data[start..start + (x1 - x0) as usize * pixel_size]);
         }
+
+        frames.push(Frame {
+            control: FrameControl {
+                width: x1 - x0,
+                height: y1 - y0,
+                x_offset: x0,
+                y_offset: y0,
+                dispose_op: DisposeOp::None,
+                blend_op: BlendOp::Source,
+                ..control
+            },
+            image: Image {
+                width: x1 - x0,
+                height: y1 - y0,
+                img_type: canvas.img_type,
+                data,
+            },
+        });
+    }
+
+    frames
+}
+
+///Encodes an animated png into a byte stream.
+///
+///If the animation has no default image, the first frame is used as the default image and has to
+///cover the whole canvas
+///
+///# Errors
+///
+///Returns an error if the animation has no frames, if the frames are floating point, if a frame
+///doesn't fit on the canvas, if the frames and the default image have different types or if the
+///options contain invalid text keywords
+#[allow(clippy::too_many_lines)]
+pub fn encode_apng(
+    animation: &Animation,
+    options: &PngEncodingOptions,
+) -> std::io::Result<Vec<u8>> {
+    let invalid = |msg| Err(Error::new(ErrorKind::InvalidInput, msg));
+
+    if animation.frames.is_empty() {
+        return invalid("animation has no frames");
+    }
+
+    let img_type = animation.frames[0].image.img_type;
+
+    //Checked before compositing, which can't handle floating point or mixed frames
+    Format::try_from(img_type)?;
+
+    if animation
+        .frames
+        .iter()
+        .any(|f| f.image.img_type != img_type)
+        || animation
+            .default_image
+            .as_ref()
+            .is_some_and(|i| i.img_type != img_type)
+    {
+        return invalid("frames have different image types");
+    }
+
+    for frame in &animation.frames {
+        let control = frame.control;
+
+        if frame.image.width != control.width || frame.image.height != control.height {
+            return invalid("frame image size doesn't match the frame size");
+        }
+
+        if control.width == 0
+            || control.height == 0
+            || control.x_offset as u64 + control.width as u64 > animation.width as u64
+            || control.y_offset as u64 + control.height as u64 > animation.height as u64
+        {
+            return invalid("frame doesn't fit on the canvas");
+        }
+    }
+
+    let (frames, default_image) = if options.crop_frames {
+        let frames = crop_frames(animation, options.byte_order);
+        let default_image = animation.default_image.clone().map(|mut image| {
+            image.add_channels();
+            image.add_alpha();
+            image
+        });
+
+        (frames, default_image)
     } else {
-        for (ind, d) in image.data.iter().enumerate() {
-            if (ind as u32).is_multiple_of(scanline_size) {
-                image_data.push(0);
-            }
+        (animation.frames.clone(), animation.default_image.clone())
+    };
+
+    //Cropped frames are converted to rgba
+    let format = Format::try_from(frames[0].image.img_type)?;
+
+    if let Some(image) = &default_image {
+        if image.width != animation.width || image.height != animation.height {
+            return invalid("default image size doesn't match the canvas size");
+        }
+    } else {
+        let control = frames[0].control;
 
-            image_data.push(*d);
+        if control.x_offset != 0
+            || control.y_offset != 0
+            || control.width != animation.width
+            || control.height != animation.height
+        {
+            return invalid("first frame has to cover the whole canvas");
         }
     }
 
-    let mut enc = flate2::write::ZlibEncoder::new(vec![0x49, 0x44, 0x41, 0x54], profile);
-    enc.write_all(&image_data).unwrap();
-    let compressed = enc.finish().unwrap();
+    let mut writer = Vec::new();
+    write_header(
+        &mut writer,
+        animation.width,
+        animation.height,
+        format,
+        None,
+        options,
+    )?;
+
+    //acTL
+    let mut actl = b"acTL".to_vec();
+    actl.extend_from_slice(&(frames.len() as u32).to_be_bytes());
+    actl.extend_from_slice(&animation.num_plays.to_be_bytes());
+    write_chunk(&mut writer, &actl)?;
+
+    let mut sequence = 0;
+
+    let (default_image, frames) = if let Some(image) = &default_image {
+        (image, &frames[..])
+    } else {
+        write_chunk(&mut writer, &frame_control(sequence, &frames[0].control))?;
+        sequence += 1;
+
+        (&frames[0].image, &frames[1..])
+    };
+
+    let mut encoder = Encoder::image_data(
+        writer,
+        animation.width,
+        animation.height,
+        format,
+        options,
+        None,
+    );
+    encoder.write_rows(&default_image.data)?;
+    (writer, _) = encoder.finish_data()?;
 
-    let len: U32BE = (compressed.len() as u32 - 4).into();
-    let crc: U32BE = compute_crc(&compressed).into();
+    for frame in frames {
+        write_chunk(&mut writer, &frame_control(sequence, &frame.control))?;
 
-    //Data chunk
-    stream.extend_from_slice(bytemuck::bytes_of(&len));
-    stream.extend_from_slice(&compressed);
-    stream.extend_from_slice(bytemuck::bytes_of(&crc));
+        let mut encoder = Encoder::image_data(
+            writer,
+            frame.control.width,
+            frame.control.height,
+            format,
+            options,
+            Some(sequence + 1),
+        );
+        encoder.write_rows(&frame.image.data)?;
+
+        (writer, sequence) = encoder.finish_data()?;
+    }
 
     //end
-    let data = [0x49u8, 0x45, 0x4e, 0x44];
-    let len = 0u32;
-    let crc: U32BE = compute_crc(&data).into();
+    write_chunk(&mut writer, &[0x49u8, 0x45, 0x4e, 0x44])?;
+
+    Ok(writer)
+}
+
+///Encodes an indexed png into a byte stream, storing the indices with the bit depth of the image
+///
+///# Panics
+///
+///Panics if [`try_encode_indexed_png`] returns an error
+#[must_use]
+pub fn encode_indexed_png(image: &IndexedImage, options: &PngEncodingOptions) -> Vec<u8> {
+    try_encode_indexed_png(image, options).expect("Invalid indexed image or encoding options")
+}
 
-    stream.extend_from_slice(bytemuck::bytes_of(&len));
-    stream.extend_from_slice(&data);
-    stream.extend_from_slice(bytemuck::bytes_of(&crc));
+///Encodes an indexed png into a byte stream, storing the indices with the bit depth of the image
+///
+///# Errors
+///
+///Returns an error if the length of the image data doesn't match its dimensions, an index is
+///outside of the palette, the palette doesn't fit the bit depth, or if the options contain invalid
+///metadata, see [`try_encode_png`]
+pub fn try_encode_indexed_png(
+    image: &IndexedImage,
+    options: &PngEncodingOptions,
+) -> std::io::Result<Vec<u8>> {
+    let mut encoder = Encoder::new_indexed(
+        Vec::new(),
+        image.width,
+        image.height,
+        image.bit_depth,
+        &image.palette,
+        options,
+    )?;
 
-    stream
+    encoder.write_rows(&image.data)?;
+    encoder.finish()
 }
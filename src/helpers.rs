@@ -39,6 +39,8 @@ pub enum ChunkType {
     acTL,
     fcTL,
     fdAT,
+    ///Any chunk not defined above
+    Unknown([u8; 4]),
 }
 
 #[derive(Debug, Clone, Copy)]
@@ -50,14 +52,60 @@ pub enum ColorType {
     TruecolorAlpha,
 }
 
-pub fn to_color_type(t: u8) -> ColorType {
+impl ColorType {
+    ///Number of samples per pixel
+    pub const fn channels(self) -> u32 {
+        match self {
+            Self::Greyscale | Self::IndexedColor => 1,
+            Self::GreyscaleAlpha => 2,
+            Self::Truecolor => 3,
+            Self::TruecolorAlpha => 4,
+        }
+    }
+}
+
+///Length of a scanline in bytes, not including the filter type byte
+pub const fn scanline_bytes(width: u32, color_type: ColorType, bit_depth: u8) -> usize {
+    (width as usize * color_type.channels() as usize * bit_depth as usize).div_ceil(8)
+}
+
+///Adam7 passes, stored as (x start, y start, x step, y step)
+pub const ADAM7_PASSES: [(u32, u32, u32, u32); 7] = [
+    (0, 0, 8, 8),
+    (4, 0, 8, 8),
+    (0, 4, 4, 8),
+    (2, 0, 4, 4),
+    (0, 2, 2, 4),
+    (1, 0, 2, 2),
+    (0, 1, 1, 2),
+];
+
+///Returns the width and height of an Adam7 pass, either can be 0 for small images
+pub const fn pass_size(width: u32, height: u32, pass: (u32, u32, u32, u32)) -> (u32, u32) {
+    let (x0, y0, dx, dy) = pass;
+
+    let w = if width > x0 {
+        (width - x0).div_ceil(dx)
+    } else {
+        0
+    };
+    let h = if height > y0 {
+        (height - y0).div_ceil(dy)
+    } else {
+        0
+    };
+
+    (w, h)
+}
+
+pub const fn to_color_type(t: u8) -> Result<ColorType, Error> {
     match t {
-        0 => ColorType::Greyscale,
-        2 => ColorType::Truecolor,
-        3 => ColorType::IndexedColor,
-        4 => ColorType::GreyscaleAlpha,
-        6 => ColorType::TruecolorAlpha,
-        _ => unreachable!(),
+        0 => Ok(ColorType::Greyscale),
+        2 => Ok(ColorType::Truecolor),
+        3 => Ok(ColorType::IndexedColor),
+        4 => Ok(ColorType::GreyscaleAlpha),
+        6 => Ok(ColorType::TruecolorAlpha),
+        _ => Err(Error::UnknownColorType(t)),
     }
 }
 
@@ -87,11 +135,11 @@ impl TrnsPallete {
     }
 
     pub fn get(&self, index: u8) -> u8 {
-        if index >= self.inner.len() as u8 {
-            255
-        } else {
-            self.inner[index as usize]
-        }
+        self.inner.get(index as usize).copied().unwrap_or(255)
+    }
+
+    pub fn values(&self) -> &[u8] {
+        &self.inner
     }
 }
 
@@ -107,94 +155,59 @@ impl Pallete {
         Self { inner: data }
     }
 
-    pub fn get(&self, index: u8) -> &[u8] {
-        let index = index as usize * 3;
-
-        &self.inner[index..index + 3]
-    }
-}
-
-pub struct Chunk {
-    pub chunk_type: ChunkType,
-    pub data: Vec<u8>,
-}
-
-pub fn get_chunk_type(data: [u8; 4]) -> Result<ChunkType, Error> {
-    let Ok(string) = String::from_utf8(data.to_vec()) else {
-        return Err(Error::InvalidChunkType);
-    };
+    pub fn get(&self, index: u8) -> Result<&[u8], Error> {
+        let start = index as usize * 3;
 
-    match string.as_str() {
-        "IHDR" => Ok(ChunkType::IHDR),
-        "PLTE" => Ok(ChunkType::PLTE),
-        "IDAT" => Ok(ChunkType::IDAT),
-        "IEND" => Ok(ChunkType::IEND),
-        "tRNS" => Ok(ChunkType::tRNS),
-        "cHRM" => Ok(ChunkType::cHRM),
-        "gAMA" => Ok(ChunkType::gAMA),
-        "iCCP" => Ok(ChunkType::iCCP),
-        "sBIT" => Ok(ChunkType::sBIT),
-        "sRGB" => Ok(ChunkType::sRGB),
-        "cICP" => Ok(ChunkType::cICP),
-        "mDCv" => Ok(ChunkType::mDCv),
-        "iTXt" => Ok(ChunkType::iTXt),
-        "tEXt" => Ok(ChunkType::tEXt),
-        "zTXt" => Ok(ChunkType::zTXt),
-        "bKGD" => Ok(ChunkType::bKGD),
-        "hIST" => Ok(ChunkType::hIST),
-        "pHYs" => Ok(ChunkType::pHYs),
-        "sPLT" => Ok(ChunkType::sPLT),
-        "eXIf" => Ok(ChunkType::eXIf),
-        "tIME" => Ok(ChunkType::tIME),
-        "acTL" => Ok(ChunkType::acTL),
-        "fcTL" => Ok(ChunkType::fcTL),
-        "fdAT" => Ok(ChunkType::fdAT),
-        _ => Err(Error::InvalidChunkType),
+        self.inner
+            .get(start..start + 3)
+            .ok_or(Error::PaletteIndexOutOfRange(index))
     }
-}
 
-pub fn read_n_const<T: Default + Copy, const N: usize>(
-    stream: &mut impl Iterator<Item = T>,
-) -> [T; N] {
-    let mut output = [T::default(); N];
-
-    for i in &mut output {
-        *i = stream.next().unwrap();
+    pub fn colors(&self) -> Vec<[u8; 3]> {
+        self.inner
+            .chunks_exact(3)
+            .map(|c| [c[0], c[1], c[2]])
+            .collect()
     }
-
-    output
 }
 
-pub fn read_n<T: Default + Copy>(stream: &mut impl Iterator<Item = T>, n: u32) -> Vec<T> {
-    let mut o = Vec::new();
-    for _ in 0..n {
-        o.push(stream.next().unwrap());
-    }
-    o
-}
-
-//TODO error handling
-pub fn parse_chunk(stream: &mut impl Iterator<Item = u8>) -> Result<Chunk, Error> {
-    let length = u32::from_be_bytes(read_n_const(stream));
-    //Read type + data
-    let mut data = read_n(stream, length + 4);
-
-    let computed_crc = u32::to_be_bytes(compute_crc(&data));
-    let crc = read_n_const(stream);
-
-    if computed_crc != crc {
-        return Err(Error::InvalidCrc);
-    }
-
-    let mut chunk_type = [0; 4];
-
-    for d in &mut chunk_type {
-        *d = data.remove(0);
+pub const fn get_chunk_type(data: [u8; 4]) -> Result<ChunkType, Error> {
+    //Chunk types are restricted to ascii letters
+    if !(data[0].is_ascii_alphabetic()
+        && data[1].is_ascii_alphabetic()
+        && data[2].is_ascii_alphabetic()
+        && data[3].is_ascii_alphabetic())
+    {
+        return Err(Error::InvalidChunkType);
     }
 
-    let chunk_type = get_chunk_type(chunk_type).unwrap();
-
-    Ok(Chunk { chunk_type, data })
+    Ok(match &data {
+        b"IHDR" => ChunkType::IHDR,
+        b"PLTE" => ChunkType::PLTE,
+        b"IDAT" => ChunkType::IDAT,
+        b"IEND" => ChunkType::IEND,
+        b"tRNS" => ChunkType::tRNS,
+        b"cHRM" => ChunkType::cHRM,
+        b"gAMA" => ChunkType::gAMA,
+        b"iCCP" => ChunkType::iCCP,
+        b"sBIT" => ChunkType::sBIT,
+        b"sRGB" => ChunkType::sRGB,
+        b"cICP" => ChunkType::cICP,
+        b"mDCv" => ChunkType::mDCv,
+        b"iTXt" => ChunkType::iTXt,
+        b"tEXt" => ChunkType::tEXt,
+        b"zTXt" => ChunkType::zTXt,
+        b"bKGD" => ChunkType::bKGD,
+        b"hIST" => ChunkType::hIST,
+        b"pHYs" => ChunkType::pHYs,
+        b"sPLT" => ChunkType::sPLT,
+        b"eXIf" => ChunkType::eXIf,
+        b"tIME" => ChunkType::tIME,
+        b"acTL" => ChunkType::acTL,
+        b"fcTL" => ChunkType::fcTL,
+        b"fdAT" => ChunkType::fdAT,
+        _ => ChunkType::Unknown(data),
+    })
 }
 
 ///Statically computed table for fast CRC computation
@@ -223,18 +236,26 @@ const fn compute_crc_table() -> [u32; 256] {
     output
 }
 
+static CRC_TABLE: [u32; 256] = compute_crc_table();
+
 //Copied from sample CRC implementation https://www.w3.org/TR/png-3/#samplecrc
-///Calculates 32bit CRC
-pub fn compute_crc(data: &[u8]) -> u32 {
-    let mut c = u32::MAX;
+///Updates a running CRC with the given data. The running CRC should be initialized to all 1s,
+///and the final value inverted
+pub fn update_crc(crc: u32, data: &[u8]) -> u32 {
+    let mut c = crc;
 
-    let table = compute_crc_table();
     for i in data {
         let i = *i as u32;
-        c = table[((c ^ i) & 0xff) as usize] ^ (c >> 8);
+        c = CRC_TABLE[((c ^ i) & 0xff) as usize] ^ (c >> 8);
     }
 
-    c ^ u32::MAX
+    c
+}
+
+///Calculates 32bit CRC
+#[cfg(feature = "encoding")]
+pub fn compute_crc(data: &[u8]) -> u32 {
+    update_crc(u32::MAX, data) ^ u32::MAX
 }
 
 ///Merges 2 u8 to create a u16
@@ -242,78 +263,22 @@ pub const fn to_u16(a: u8, b: u8) -> u16 {
     (a as u16) | ((b as u16) << 8)
 }
 
-pub struct Filtered {
-    pub data: Vec<u8>,
-    pub color_type: ColorType,
-    pub scanline_len: u32,
-    pub bit_depth: u8,
-    pub ignore_0: bool,
-}
-
-impl Filtered {
-    pub fn set(&mut self, index: usize, val: u8) {
-        self.data[index] = val;
-    }
-    //Bytes
-    //  |c|b|
-    //  |a|x|
-    //  x = current
-    pub fn get_a(&self, index: usize) -> u8 {
-        let offset = match self.color_type {
-            ColorType::IndexedColor | ColorType::Greyscale => 1,
-            ColorType::Truecolor => 3,
-            ColorType::GreyscaleAlpha => 2,
-            ColorType::TruecolorAlpha => 4,
-        } * if self.bit_depth == 16 { 2 } else { 1 };
-
-        if index as u32 % self.scanline_len < offset as u32 {
-            return 0;
-        }
-
-        let final_index = index - offset;
-
-        if (final_index as u32).is_multiple_of(self.scanline_len) && self.ignore_0 {
-            return 0;
-        }
-
-        self.data.get(final_index).copied().unwrap_or(0)
-    }
-
-    pub fn get_b(&self, index: usize) -> u8 {
-        if index as u32 >= self.scanline_len {
-            self.data
-                .get(index - self.scanline_len as usize)
-                .copied()
-                .unwrap_or(0)
-        } else {
-            0
-        }
-    }
-
-    pub fn get_c(&self, index: usize) -> u8 {
-        if index as u32 > self.scanline_len {
-            self.get_a(index - self.scanline_len as usize)
-        } else {
-            0
-        }
-    }
-
-    pub fn paeth(&self, index: usize) -> u8 {
-        let a = self.get_a(index) as i16;
-        let b = self.get_b(index) as i16;
-        let c = self.get_c(index) as i16;
-
-        let p = a + b - c;
-        let pa = i16::abs(p - a);
-        let pb = i16::abs(p - b);
-        let pc = i16::abs(p - c);
-
-        if pa <= pb && pa <= pc {
-            a as u8
-        } else if pb <= pc {
-            b as u8
-        } else {
-            c as u8
-        }
+///Paeth predictor, a = left, b = above, c = upper left
+pub const fn paeth_predictor(a: u8, b: u8, c: u8) -> u8 {
+    let a = a as i16;
+    let b = b as i16;
+    let c = c as i16;
+
+    let p = a + b - c;
+    let pa = i16::abs(p - a);
+    let pb = i16::abs(p - b);
+    let pc = i16::abs(p - c);
+
+    if pa <= pb && pa <= pc {
+        a as u8
+    } else if pb <= pc {
+        b as u8
+    } else {
+        c as u8
     }
 }
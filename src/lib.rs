@@ -9,6 +9,9 @@
 //!- Encoding
 //!- Decoding
 //!
+//!The optional `chrono` feature adds conversions from `chrono` date times to
+//![`ModificationTime`]
+//!
 //!# Usage
 //!
 //! ```no_run
@@ -34,6 +37,7 @@
 )]
 use std::fmt::Debug;
 
+mod animation;
 #[cfg(feature = "decoding")]
 mod decoding;
 #[cfg(feature = "encoding")]
@@ -41,13 +45,77 @@ mod encoding;
 
 #[cfg(any(feature = "decoding", feature = "encoding"))]
 mod helpers;
+mod metadata;
+#[cfg(all(feature = "decoding", feature = "encoding"))]
+mod optimize;
+mod palette;
+#[cfg(feature = "encoding")]
+mod quantize;
 #[cfg(test)]
 mod tests;
 
+pub use animation::{Animation, AnimationControl, BlendOp, DisposeOp, Frame, FrameControl};
 #[cfg(feature = "decoding")]
-pub use decoding::{Error, decode_png};
+pub use decoding::{
+    ColorConversion, Decoder, DecodingOptions, Error, decode_apng, decode_indexed_png, decode_png,
+    decode_png_with_metadata, decode_png_with_options,
+};
 #[cfg(feature = "encoding")]
-pub use encoding::{CompressionLevel, PngEncodingOptions, encode_png};
+pub use encoding::{
+    CompressionLevel, Encoder, FilterStrategy, PngEncodingOptions, encode_apng, encode_indexed_png,
+    encode_png, try_encode_indexed_png, try_encode_png,
+};
+pub use metadata::{
+    Background, Chromaticities, CodingIndependentCodePoints, Gamma, IccProfile, InternationalText,
+    MasteringDisplay, Metadata, ModificationTime, Orientation, PhysicalDimensions, PixelUnit,
+    RenderingIntent, TextChunk, UnknownChunk,
+};
+#[cfg(all(feature = "decoding", feature = "encoding"))]
+pub use optimize::{OptimizationOptions, OptimizationReport, optimize};
+pub use palette::{IndexedImage, Palette};
+#[cfg(feature = "encoding")]
+pub use quantize::{Quantization, quantize};
+
+///Byte order of the 16 bit samples stored in [`Image::data`]
+#[derive(Debug, Default, PartialEq, Eq, Clone, Copy)]
+pub enum ByteOrder {
+    ///Least significant byte first
+    #[default]
+    LittleEndian,
+    ///Most significant byte first, the order used inside png files
+    BigEndian,
+    ///Byte order of the target platform, needed to access the samples as `u16`
+    Native,
+}
+
+impl ByteOrder {
+    ///Converts a sample into bytes of this order
+    pub(crate) const fn to_bytes(self, value: u16) -> [u8; 2] {
+        match self {
+            Self::LittleEndian => value.to_le_bytes(),
+            Self::BigEndian => value.to_be_bytes(),
+            Self::Native => value.to_ne_bytes(),
+        }
+    }
+
+    ///Reads a sample stored in this order
+    pub(crate) const fn read(self, bytes: [u8; 2]) -> u16 {
+        match self {
+            Self::LittleEndian => u16::from_le_bytes(bytes),
+            Self::BigEndian => u16::from_be_bytes(bytes),
+            Self::Native => u16::from_ne_bytes(bytes),
+        }
+    }
+
+    ///Whether the most significant byte comes first
+    const fn is_big_endian(self) -> bool {
+        match self {
+            Self::LittleEndian => false,
+            Self::BigEndian => true,
+            Self::Native => cfg!(target_endian = "big"),
+        }
+    }
+}
 
 #[derive(Debug, PartialEq, Eq, Clone, Copy)]
 ///Image type of a loaded image
@@ -68,13 +136,76 @@ pub enum ImageType {
     Rgb16,
     ///An rgba image with the bit depth of 16
     Rgba16,
+    ///A grayscale image with bit depth of 1, every sample takes a byte and is either 0 or 1
+    R1,
+    ///A grayscale image with bit depth of 2, every sample takes a byte and ranges from 0 to 3
+    R2,
+    ///A grayscale image with bit depth of 4, every sample takes a byte and ranges from 0 to 15
+    R4,
+    ///A grayscale image with 32 bit floating point samples, stored in native byte order
+    R32F,
+    ///A grayscale image with an alpha channel and 32 bit floating point samples, stored in native
+    ///byte order
+    Ra32F,
+    ///An rgb image with 32 bit floating point samples, stored in native byte order
+    Rgb32F,
+    ///An rgba image with 32 bit floating point samples, stored in native byte order
+    Rgba32F,
 }
 
 impl ImageType {
     ///Returns whether the image format is 16 bit or not
     #[must_use]
     pub const fn is_16_bit(&self) -> bool {
-        !matches!(self, Self::R8 | Self::Ra8 | Self::Rgb8 | Self::Rgba8)
+        matches!(self, Self::R16 | Self::Ra16 | Self::Rgb16 | Self::Rgba16)
+    }
+
+    ///Returns whether the image format uses floating point samples
+    #[must_use]
+    pub const fn is_float(&self) -> bool {
+        matches!(
+            self,
+            Self::R32F | Self::Ra32F | Self::Rgb32F | Self::Rgba32F
+        )
+    }
+
+    ///Returns the number of bits used by a single sample of this format
+    #[must_use]
+    pub const fn bit_depth(&self) -> u8 {
+        match self {
+            Self::R1 => 1,
+            Self::R2 => 2,
+            Self::R4 => 4,
+            Self::R8 | Self::Ra8 | Self::Rgb8 | Self::Rgba8 => 8,
+            Self::R16 | Self::Ra16 | Self::Rgb16 | Self::Rgba16 => 16,
+            Self::R32F | Self::Ra32F | Self::Rgb32F | Self::Rgba32F => 32,
+        }
+    }
+
+    ///Returns the number of channels of this format
+    #[must_use]
+    pub const fn channels(&self) -> usize {
+        match self {
+            Self::R1 | Self::R2 | Self::R4 | Self::R8 | Self::R16 | Self::R32F => 1,
+            Self::Ra8 | Self::Ra16 | Self::Ra32F => 2,
+            Self::Rgb8 | Self::Rgb16 | Self::Rgb32F => 3,
+            Self::Rgba8 | Self::Rgba16 | Self::Rgba32F => 4,
+        }
+    }
+
+    ///Returns the number of bytes a single pixel of this format takes
+    #[must_use]
+    pub const fn bytes_per_pixel(&self) -> usize {
+        match self {
+            Self::R1 | Self::R2 | Self::R4 | Self::R8 => 1,
+            Self::R16 | Self::Ra8 => 2,
+            Self::Rgb8 => 3,
+            Self::Ra16 | Self::Rgba8 | Self::R32F => 4,
+            Self::Rgb16 => 6,
+            Self::Rgba16 | Self::Ra32F => 8,
+            Self::Rgb32F => 12,
+            Self::Rgba32F => 16,
+        }
     }
 }
 
@@ -87,7 +218,15 @@ pub struct Image {
     pub height: u32,
     ///Type of the image
     pub img_type: ImageType,
-    ///Actual data. Data in an image is stored in scanlines, going left to right, top to bottom
+    ///Actual data. Data in an image is stored in scanlines, going left to right, top to bottom.
+    ///
+    ///16 bit samples take 2 bytes, stored little endian unless a different [`ByteOrder`] was
+    ///requested when decoding. Floating point samples take 4 bytes, stored in native byte order.
+    ///
+    ///The byte order isn't stored with the image. [`Self::flatten`] and [`Animation::composite`]
+    ///take the order of the samples as a parameter, [`Self::as_u16`], [`Self::get_pixel`] and
+    ///[`Self::put_pixel`] use [`ByteOrder::Native`]. [`Self::convert_byte_order`] converts between
+    ///orders
     pub data: Vec<u8>,
 }
 
@@ -103,8 +242,12 @@ impl Debug for Image {
 }
 
 impl Image {
-    ///Adds an alpha channel to the image, does nothing if the image already contains an alpha channel
+    ///Adds an alpha channel to the image, does nothing if the image already contains an alpha channel.
+    ///Grayscale images with a bit depth below 8 are converted to `Ra8`. 16 bit samples can use any
+    ///byte order
     pub fn add_alpha(&mut self) {
+        self.expand_bit_depth();
+
         match self.img_type {
             ImageType::R8 => {
                 self.img_type = ImageType::Ra8;
@@ -134,12 +277,29 @@ impl Image {
                     .flat_map(|c| [c[0], c[1], c[2], c[3], c[4], c[5], 0xff, 0xff])
                     .collect();
             }
+            ImageType::R32F | ImageType::Rgb32F => {
+                let channels = self.img_type.channels();
+                self.img_type = if channels == 1 {
+                    ImageType::Ra32F
+                } else {
+                    ImageType::Rgba32F
+                };
+                self.data = self
+                    .data
+                    .chunks(channels * 4)
+                    .flat_map(|c| c.iter().copied().chain(1.0f32.to_ne_bytes()))
+                    .collect();
+            }
             _ => {}
         }
     }
 
-    ///Adds channels to a grayscale image. Does nothing if the image is not grayscale
+    ///Adds channels to a grayscale image. Does nothing if the image is not grayscale. Grayscale
+    ///images with a bit depth below 8 are converted to `Rgb8`. 16 bit samples can use any byte
+    ///order
     pub fn add_channels(&mut self) {
+        self.expand_bit_depth();
+
         match self.img_type {
             ImageType::R8 => {
                 self.img_type = ImageType::Rgb8;
@@ -169,7 +329,326 @@ impl Image {
                     .flat_map(|i| [i[0], i[1], i[0], i[1], i[0], i[1], i[2], i[3]])
                     .collect();
             }
+            ImageType::R32F => {
+                self.img_type = ImageType::Rgb32F;
+                self.data = self.data.chunks(4).flat_map(|i| i.repeat(3)).collect();
+            }
+            ImageType::Ra32F => {
+                self.img_type = ImageType::Rgba32F;
+                self.data = self
+                    .data
+                    .chunks(8)
+                    .flat_map(|i| [&i[..4], &i[..4], i].concat())
+                    .collect();
+            }
             _ => {}
         }
     }
+
+    ///Converts the samples of a 16 bit image from one byte order to another. Does nothing if the
+    ///image is not 16 bit
+    ///
+    ///# Examples
+    ///
+    ///```
+    ///# use lunar_png::*;
+    ///let mut image = Image {
+    ///    width: 1,
+    ///    height: 1,
+    ///    img_type: ImageType::R16,
+    ///    data: vec![0x12, 0x34],
+    ///};
+    ///
+    ///image.convert_byte_order(ByteOrder::BigEndian, ByteOrder::Native);
+    ///
+    ///assert_eq!(image.get_pixel(0, 0), Some([0x1234]));
+    ///```
+    pub fn convert_byte_order(&mut self, from: ByteOrder, to: ByteOrder) {
+        if self.img_type.is_16_bit() && from.is_big_endian() != to.is_big_endian() {
+            self.data.chunks_exact_mut(2).for_each(|s| s.swap(0, 1));
+        }
+    }
+
+    ///Views the samples of a 16 bit image as `u16`s, which requires the data to be in
+    ///[`ByteOrder::Native`]. Returns `None` if the image is not 16 bit, or if the data isn't
+    ///aligned for `u16`
+    #[must_use]
+    pub fn as_u16(&self) -> Option<&[u16]> {
+        if !self.img_type.is_16_bit() {
+            return None;
+        }
+
+        bytemuck::try_cast_slice(&self.data).ok()
+    }
+
+    ///Mutable version of [`Self::as_u16`]
+    #[must_use]
+    pub fn as_u16_mut(&mut self) -> Option<&mut [u16]> {
+        if !self.img_type.is_16_bit() {
+            return None;
+        }
+
+        bytemuck::try_cast_slice_mut(&mut self.data).ok()
+    }
+
+    ///Views the samples of a floating point image as `f32`s. Returns `None` if the image is not
+    ///floating point, or if the data isn't aligned for `f32`
+    #[must_use]
+    pub fn as_f32(&self) -> Option<&[f32]> {
+        if !self.img_type.is_float() {
+            return None;
+        }
+
+        bytemuck::try_cast_slice(&self.data).ok()
+    }
+
+    ///Mutable version of [`Self::as_f32`]
+    #[must_use]
+    pub fn as_f32_mut(&mut self) -> Option<&mut [f32]> {
+        if !self.img_type.is_float() {
+            return None;
+        }
+
+        bytemuck::try_cast_slice_mut(&mut self.data).ok()
+    }
+
+    ///Returns the channels of a pixel. Returns `None` if the pixel is outside of the image, if
+    ///`N` doesn't match the number of channels of the image, or if the image is floating point.
+    ///16 bit samples are read in [`ByteOrder::Native`]
+    ///
+    ///# Examples
+    ///
+    ///```
+    ///# use lunar_png::*;
+    ///let image = Image {
+    ///    width: 1,
+    ///    height: 1,
+    ///    img_type: ImageType::Rgb8,
+    ///    data: vec![255, 128, 0],
+    ///};
+    ///
+    ///assert_eq!(image.get_pixel::<3>(0, 0), Some([255, 128, 0]));
+    ///```
+    #[must_use]
+    pub fn get_pixel<const N: usize>(&self, x: u32, y: u32) -> Option<[u16; N]> {
+        let index = self.pixel_index::<N>(x, y)?;
+        let pixel = self
+            .data
+            .get(index..index + self.img_type.bytes_per_pixel())?;
+
+        Some(std::array::from_fn(|channel| {
+            if self.img_type.is_16_bit() {
+                u16::from_ne_bytes([pixel[channel * 2], pixel[channel * 2 + 1]])
+            } else {
+                pixel[channel] as u16
+            }
+        }))
+    }
+
+    ///Sets the channels of a pixel. 16 bit samples are written in [`ByteOrder::Native`], values
+    ///of 8 bit images are truncated to 8 bits
+    ///
+    ///# Panics
+    ///
+    ///Panics if the pixel is outside of the image, if `N` doesn't match the number of channels
+    ///of the image, or if the image is floating point
+    pub fn put_pixel<const N: usize>(&mut self, x: u32, y: u32, pixel: [u16; N]) {
+        let index = self.pixel_index::<N>(x, y).expect(
+            "Pixel outside of the image, invalid number of channels or floating point image",
+        );
+        let is_16_bit = self.img_type.is_16_bit();
+
+        let data = &mut self.data[index..index + self.img_type.bytes_per_pixel()];
+
+        for (channel, value) in pixel.into_iter().enumerate() {
+            if is_16_bit {
+                data[channel * 2..channel * 2 + 2].copy_from_slice(&value.to_ne_bytes());
+            } else {
+                data[channel] = value as u8;
+            }
+        }
+    }
+
+    ///Index of the first byte of a pixel, `None` if the pixel is outside of the image or `N` is
+    ///not the number of channels
+    const fn pixel_index<const N: usize>(&self, x: u32, y: u32) -> Option<usize> {
+        if x >= self.width
+            || y >= self.height
+            || N != self.img_type.channels()
+            || self.img_type.is_float()
+        {
+            return None;
+        }
+
+        Some((y as usize * self.width as usize + x as usize) * self.img_type.bytes_per_pixel())
+    }
+
+    ///Composites an image with an alpha channel over a solid background, removing the alpha
+    ///channel. Grayscale images stay grayscale unless the background has a color, in which case
+    ///they are converted to rgb. Background samples use the bit depth of the image, 16 bit
+    ///samples are read and written in the given byte order. Does nothing if the image has no
+    ///alpha channel or is floating point
+    ///
+    ///# Examples
+    ///
+    ///```
+    ///# use lunar_png::*;
+    ///let mut image = Image {
+    ///    width: 1,
+    ///    height: 1,
+    ///    img_type: ImageType::Ra8,
+    ///    data: vec![0, 0],
+    ///};
+    ///
+    ///image.flatten(Background::Rgb([255, 0, 0]), ByteOrder::LittleEndian);
+    ///
+    ///assert_eq!(image.img_type, ImageType::Rgb8);
+    ///assert_eq!(image.data, [255, 0, 0]);
+    ///```
+    pub fn flatten(&mut self, background: Background, byte_order: ByteOrder) {
+        if !matches!(
+            self.img_type,
+            ImageType::Ra8 | ImageType::Ra16 | ImageType::Rgba8 | ImageType::Rgba16
+        ) {
+            return;
+        }
+
+        let background = match background {
+            Background::Gray(v) => [v; 3],
+            Background::Rgb(color) => color,
+        };
+
+        if background[0] != background[1] || background[0] != background[2] {
+            self.add_channels();
+        }
+
+        let is_16_bit = self.img_type.is_16_bit();
+        let sample_size = if is_16_bit { 2 } else { 1 };
+        let max: u64 = if is_16_bit { 0xffff } else { 0xff };
+        let channels = self.img_type.channels() - 1;
+
+        let read = |sample: &[u8]| {
+            if is_16_bit {
+                byte_order.read([sample[0], sample[1]]) as u64
+            } else {
+                sample[0] as u64
+            }
+        };
+
+        let mut data = Vec::with_capacity(self.data.len() / (channels + 1) * channels);
+
+        for pixel in self.data.chunks_exact(self.img_type.bytes_per_pixel()) {
+            let alpha = read(&pixel[channels * sample_size..]);
+
+            for (channel, background) in background.iter().enumerate().take(channels) {
+                let color = read(&pixel[channel * sample_size..]);
+                let background = (*background as u64).min(max);
+
+                let value = (color * alpha + background * (max - alpha) + max / 2) / max;
+
+                if is_16_bit {
+                    data.extend_from_slice(&byte_order.to_bytes(value as u16));
+                } else {
+                    data.push(value as u8);
+                }
+            }
+        }
+
+        self.img_type = match self.img_type {
+            ImageType::Ra8 => ImageType::R8,
+            ImageType::Ra16 => ImageType::R16,
+            ImageType::Rgba8 => ImageType::Rgb8,
+            _ => ImageType::Rgb16,
+        };
+        self.data = data;
+    }
+
+    ///Rotates and mirrors the image so that an image stored with the given orientation is upright.
+    ///Rotations by 90 or 270 degrees swap the width and the height. 16 bit samples can use any
+    ///byte order
+    pub fn apply_orientation(&mut self, orientation: Orientation) {
+        if orientation == Orientation::Normal {
+            return;
+        }
+
+        let (width, height) = (self.width as usize, self.height as usize);
+        let pixel_size = self.img_type.bytes_per_pixel();
+
+        let transposed = matches!(
+            orientation,
+            Orientation::Transpose
+                | Orientation::Rotate90
+                | Orientation::Transverse
+                | Orientation::Rotate270
+        );
+        let (new_width, new_height) = if transposed {
+            (height, width)
+        } else {
+            (width, height)
+        };
+
+        let mut data = Vec::with_capacity(self.data.len());
+
+        for y in 0..new_height {
+            for x in 0..new_width {
+                //Position of the source pixel
+                let (sx, sy) = match orientation {
+                    Orientation::Normal => (x, y),
+                    Orientation::FlipHorizontal => (width - 1 - x, y),
+                    Orientation::Rotate180 => (width - 1 - x, height - 1 - y),
+                    Orientation::FlipVertical => (x, height - 1 - y),
+                    Orientation::Transpose => (y, x),
+                    Orientation::Rotate90 => (y, height - 1 - x),
+                    Orientation::Transverse => (width - 1 - y, height - 1 - x),
+                    Orientation::Rotate270 => (width - 1 - y, x),
+                };
+
+                let index = (sy * width + sx) * pixel_size;
+                data.extend_from_slice(&self.data[index..index + pixel_size]);
+            }
+        }
+
+        self.width = new_width as u32;
+        self.height = new_height as u32;
+        self.data = data;
+    }
+
+    ///Converts an `R8` image to `R1`, `R2` or `R4` if every sample can be stored exactly with the
+    ///lower bit depth, picking the smallest one. This doesn't change the appearance of the image,
+    ///but lets the encoder pack several pixels into a byte. Returns whether the image was converted
+    pub fn reduce_bit_depth(&mut self) -> bool {
+        if self.img_type != ImageType::R8 {
+            return false;
+        }
+
+        for (img_type, scale) in [
+            (ImageType::R1, 255),
+            (ImageType::R2, 85),
+            (ImageType::R4, 17),
+        ] {
+            if self.data.iter().all(|v| v % scale == 0) {
+                self.img_type = img_type;
+                self.data.iter_mut().for_each(|v| *v /= scale);
+
+                return true;
+            }
+        }
+
+        false
+    }
+
+    ///Scales grayscale images with a bit depth below 8 to `R8`
+    fn expand_bit_depth(&mut self) {
+        let scale = match self.img_type {
+            ImageType::R1 => 255,
+            ImageType::R2 => 85,
+            ImageType::R4 => 17,
+            _ => return,
+        };
+
+        self.img_type = ImageType::R8;
+        self.data
+            .iter_mut()
+            .for_each(|v| *v = v.saturating_mul(scale));
+    }
 }
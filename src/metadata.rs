@@ -0,0 +1,480 @@
+///A chunk that is not recognized by the decoder
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UnknownChunk {
+    ///Type of the chunk, four ascii letters
+    pub chunk_type: [u8; 4],
+    ///Raw data of the chunk
+    pub data: Vec<u8>,
+}
+
+impl UnknownChunk {
+    ///Whether the chunk is ancillary, ancillary chunks are not needed to display the image
+    #[must_use]
+    pub const fn is_ancillary(&self) -> bool {
+        self.chunk_type[0] & 0x20 != 0
+    }
+
+    ///Whether the chunk is private, i.e. not defined by the png specification
+    #[must_use]
+    pub const fn is_private(&self) -> bool {
+        self.chunk_type[1] & 0x20 != 0
+    }
+
+    ///Whether the chunk may be copied to a modified image without knowing what it contains
+    #[must_use]
+    pub const fn is_safe_to_copy(&self) -> bool {
+        self.chunk_type[3] & 0x20 != 0
+    }
+}
+
+///Image gamma stored in the gAMA chunk, multiplied by 100000. A value of 45455 stands for a
+///gamma of 1/2.2
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Gamma(pub u32);
+
+impl Gamma {
+    ///Returns the gamma as a floating point number
+    #[must_use]
+    pub fn value(&self) -> f64 {
+        self.0 as f64 / 100_000.0
+    }
+}
+
+///Chromaticity coordinates of the white point and the primaries, stored in the cHRM chunk. Every
+///value is an x or y coordinate multiplied by 100000
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Chromaticities {
+    ///x and y of the white point
+    pub white_point: (u32, u32),
+    ///x and y of the red primary
+    pub red: (u32, u32),
+    ///x and y of the green primary
+    pub green: (u32, u32),
+    ///x and y of the blue primary
+    pub blue: (u32, u32),
+}
+
+///Rendering intent of an image in the sRGB color space, stored in the sRGB chunk
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RenderingIntent {
+    ///For images preferring good adaptation to the output device gamut, like photographs
+    Perceptual,
+    ///For images requiring color appearance matching, like logos
+    RelativeColorimetric,
+    ///For images preferring preservation of saturation, like charts
+    Saturation,
+    ///For images requiring preservation of absolute colorimetry, like proofs
+    AbsoluteColorimetric,
+}
+
+///Embedded ICC profile, stored in the iCCP chunk
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IccProfile {
+    ///Name of the profile, must be 1-79 latin-1 characters
+    pub name: String,
+    ///Decompressed profile data
+    pub data: Vec<u8>,
+}
+
+///Coding-independent code points stored in the cICP chunk, identifying the color space as
+///defined by ITU-T H.273. Used to mark HDR images, e.g. with the PQ or HLG transfer function
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CodingIndependentCodePoints {
+    ///Color primaries, 1 for BT.709 and 9 for BT.2020
+    pub color_primaries: u8,
+    ///Transfer characteristics, 13 for sRGB, 16 for PQ and 18 for HLG
+    pub transfer_characteristics: u8,
+    ///Matrix coefficients, always 0 as png only stores rgb
+    pub matrix_coefficients: u8,
+    ///Whether the samples use the full range, instead of the narrow range used by video
+    pub full_range: bool,
+}
+
+impl CodingIndependentCodePoints {
+    ///BT.2100 with the PQ transfer function
+    pub const BT2100_PQ: Self = Self {
+        color_primaries: 9,
+        transfer_characteristics: 16,
+        matrix_coefficients: 0,
+        full_range: true,
+    };
+
+    ///BT.2100 with the HLG transfer function
+    pub const BT2100_HLG: Self = Self {
+        color_primaries: 9,
+        transfer_characteristics: 18,
+        matrix_coefficients: 0,
+        full_range: true,
+    };
+
+    ///Whether the image uses the PQ transfer function
+    #[must_use]
+    pub const fn is_pq(&self) -> bool {
+        self.transfer_characteristics == 16
+    }
+
+    ///Whether the image uses the HLG transfer function
+    #[must_use]
+    pub const fn is_hlg(&self) -> bool {
+        self.transfer_characteristics == 18
+    }
+}
+
+///Color volume of the display the image was mastered on, stored in the mDCv chunk. Chromaticity
+///coordinates are multiplied by 50000, luminance values by 10000
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MasteringDisplay {
+    ///x and y of the red, green and blue primaries
+    pub primaries: [(u16, u16); 3],
+    ///x and y of the white point
+    pub white_point: (u16, u16),
+    ///Maximum luminance of the display
+    pub max_luminance: u32,
+    ///Minimum luminance of the display
+    pub min_luminance: u32,
+}
+
+impl MasteringDisplay {
+    ///Returns the maximum luminance in candelas per square meter
+    #[must_use]
+    pub fn max_luminance_nits(&self) -> f64 {
+        self.max_luminance as f64 / 10_000.0
+    }
+
+    ///Returns the minimum luminance in candelas per square meter
+    #[must_use]
+    pub fn min_luminance_nits(&self) -> f64 {
+        self.min_luminance as f64 / 10_000.0
+    }
+}
+
+///Background color to display the image on, stored in the bKGD chunk.
+///
+///Samples use the bit depth of the decoded image, so grayscale values below 8 bits are scaled to 8
+///bits unless the bit depth is preserved, and samples are shifted like the image data if the
+///significant bits are shifted. Palette indices are resolved to the color of the entry
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Background {
+    ///Gray level
+    Gray(u16),
+    ///Red, green and blue samples
+    Rgb([u16; 3]),
+}
+
+///Orientation of the image stored in the EXIF data, describing how the stored pixels have to be
+///transformed to be displayed upright
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Orientation {
+    ///Already upright
+    Normal,
+    ///Mirrored horizontally
+    FlipHorizontal,
+    ///Rotated by 180 degrees
+    Rotate180,
+    ///Mirrored vertically
+    FlipVertical,
+    ///Mirrored along the top-left to bottom-right diagonal
+    Transpose,
+    ///Has to be rotated 90 degrees clockwise
+    Rotate90,
+    ///Mirrored along the top-right to bottom-left diagonal
+    Transverse,
+    ///Has to be rotated 270 degrees clockwise
+    Rotate270,
+}
+
+impl Orientation {
+    ///Converts the value of the EXIF orientation tag, `None` if it is not 1-8
+    #[must_use]
+    pub const fn from_exif(value: u16) -> Option<Self> {
+        Some(match value {
+            1 => Self::Normal,
+            2 => Self::FlipHorizontal,
+            3 => Self::Rotate180,
+            4 => Self::FlipVertical,
+            5 => Self::Transpose,
+            6 => Self::Rotate90,
+            7 => Self::Transverse,
+            8 => Self::Rotate270,
+            _ => return None,
+        })
+    }
+}
+
+///EXIF tag storing the orientation
+const ORIENTATION_TAG: u16 = 0x0112;
+
+///Finds the value of the orientation tag in the first IFD of EXIF data. Returns the offset of the
+///value and whether the data is big endian
+pub fn find_orientation(exif: &[u8]) -> Option<(usize, bool)> {
+    let big_endian = match exif.get(..4)? {
+        b"II*\0" => false,
+        b"MM\0*" => true,
+        _ => return None,
+    };
+
+    let u16_at = |i: usize| {
+        let bytes = [*exif.get(i)?, *exif.get(i + 1)?];
+        Some(if big_endian {
+            u16::from_be_bytes(bytes)
+        } else {
+            u16::from_le_bytes(bytes)
+        })
+    };
+    let u32_at = |i: usize| {
+        let bytes = exif.get(i..i + 4)?;
+        let bytes = [bytes[0], bytes[1], bytes[2], bytes[3]];
+        Some(if big_endian {
+            u32::from_be_bytes(bytes)
+        } else {
+            u32::from_le_bytes(bytes)
+        })
+    };
+
+    let ifd = u32_at(4)? as usize;
+    let entries = u16_at(ifd)? as usize;
+
+    //Every entry takes 12 bytes: tag, type, count and the value or its offset
+    (0..entries)
+        .map(|i| ifd + 2 + i * 12)
+        .find(|entry| u16_at(*entry) == Some(ORIENTATION_TAG))
+        //The value is a single short, stored inline
+        .filter(|entry| u16_at(entry + 2) == Some(3) && u32_at(entry + 4) == Some(1))
+        .map(|entry| (entry + 8, big_endian))
+        .filter(|(value, _)| *value + 2 <= exif.len())
+}
+
+///Time of the last modification of the image, stored in the tIME chunk. Always in UTC
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct ModificationTime {
+    ///Complete year, e.g. 2024
+    pub year: u16,
+    ///Month, 1-12
+    pub month: u8,
+    ///Day of the month, 1-31
+    pub day: u8,
+    ///Hour, 0-23
+    pub hour: u8,
+    ///Minute, 0-59
+    pub minute: u8,
+    ///Second, 0-60 to allow for leap seconds
+    pub second: u8,
+}
+
+impl ModificationTime {
+    ///Returns the current time
+    ///
+    ///# Panics
+    ///
+    ///Panics if the system clock is set before 1970
+    #[must_use]
+    pub fn now() -> Self {
+        let since_epoch = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .expect("System clock is set before 1970");
+
+        Self::from_unix_time(since_epoch.as_secs())
+    }
+
+    ///Converts a unix timestamp, the number of seconds since 1970-01-01 00:00:00 UTC. Useful for
+    ///reproducible builds, e.g. with the `SOURCE_DATE_EPOCH` environment variable
+    #[must_use]
+    pub const fn from_unix_time(seconds: u64) -> Self {
+        let days = seconds / 86400;
+        let time = seconds % 86400;
+
+        //Converts days to a date in the proleptic Gregorian calendar, with years starting in
+        //March so the leap day is the last day of the year
+        let days = days + 719_468;
+        let era = days / 146_097;
+        let day_of_era = days % 146_097;
+        let year_of_era =
+            (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146_096) / 365;
+        let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+        let month = (5 * day_of_year + 2) / 153;
+        let day = day_of_year - (153 * month + 2) / 5 + 1;
+        let month = if month < 10 { month + 3 } else { month - 9 };
+        let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+
+        Self {
+            year: year as u16,
+            month: month as u8,
+            day: day as u8,
+            hour: (time / 3600) as u8,
+            minute: (time / 60 % 60) as u8,
+            second: (time % 60) as u8,
+        }
+    }
+
+    ///Whether every field is in its valid range
+    #[must_use]
+    pub const fn is_valid(&self) -> bool {
+        self.month >= 1
+            && self.month <= 12
+            && self.day >= 1
+            && self.day <= 31
+            && self.hour <= 23
+            && self.minute <= 59
+            && self.second <= 60
+    }
+}
+
+#[cfg(feature = "chrono")]
+impl<Tz: chrono::TimeZone> From<chrono::DateTime<Tz>> for ModificationTime {
+    fn from(time: chrono::DateTime<Tz>) -> Self {
+        use chrono::{Datelike, Timelike};
+
+        let time = time.naive_utc();
+
+        Self {
+            year: time.year() as u16,
+            month: time.month() as u8,
+            day: time.day() as u8,
+            hour: time.hour() as u8,
+            minute: time.minute() as u8,
+            //Leap seconds are stored as nanoseconds past the 59th second
+            second: (time.second() + time.nanosecond() / 1_000_000_000) as u8,
+        }
+    }
+}
+
+///Unit of the physical pixel dimensions
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PixelUnit {
+    ///Only the aspect ratio of the pixels is known
+    Unknown,
+    ///Pixels per meter
+    Meter,
+}
+
+///Physical size of the pixels, stored in the pHYs chunk
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PhysicalDimensions {
+    ///Pixels per unit along the x axis
+    pub x: u32,
+    ///Pixels per unit along the y axis
+    pub y: u32,
+    ///Unit of the values
+    pub unit: PixelUnit,
+}
+
+///Meters per inch
+const INCH: f64 = 0.0254;
+
+impl PhysicalDimensions {
+    ///Creates dimensions with the same resolution on both axes, given in dots per inch. The value
+    ///is rounded to whole pixels per meter, so converting back may differ slightly
+    #[must_use]
+    pub fn from_dpi(dpi: f64) -> Self {
+        let ppm = (dpi / INCH).round() as u32;
+
+        Self {
+            x: ppm,
+            y: ppm,
+            unit: PixelUnit::Meter,
+        }
+    }
+
+    ///Returns the resolution of the x and y axis in dots per inch, `None` if the unit is unknown
+    #[must_use]
+    pub fn dpi(&self) -> Option<(f64, f64)> {
+        match self.unit {
+            PixelUnit::Unknown => None,
+            PixelUnit::Meter => Some((self.x as f64 * INCH, self.y as f64 * INCH)),
+        }
+    }
+}
+
+///Language information of an international (iTXt) text chunk
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct InternationalText {
+    ///Language of the text, e.g. `en-US`. Empty if unknown, can't contain NUL
+    pub language_tag: String,
+    ///Keyword translated into the language of the text. Empty if not provided, can't contain NUL
+    pub translated_keyword: String,
+}
+
+///Textual information stored in a tEXt, zTXt or iTXt chunk
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TextChunk {
+    ///Keyword describing the text, e.g. `Author` or `Software`. Must be 1-79 latin-1 characters
+    ///other than NUL
+    pub keyword: String,
+    ///The text itself
+    pub text: String,
+    ///Whether the text is stored compressed
+    pub compressed: bool,
+    ///Language information, only present for iTXt chunks. Text that can't be represented in
+    ///latin-1 is always written as an iTXt chunk
+    pub international: Option<InternationalText>,
+}
+
+impl TextChunk {
+    ///Creates an uncompressed text entry
+    pub fn new(keyword: impl Into<String>, text: impl Into<String>) -> Self {
+        Self {
+            keyword: keyword.into(),
+            text: text.into(),
+            compressed: false,
+            international: None,
+        }
+    }
+}
+
+///Additional information stored in a png file alongside the image
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Metadata {
+    ///Ancillary chunks that the decoder did not recognize, in the order they appear in the file
+    pub unknown_chunks: Vec<UnknownChunk>,
+    ///Text entries, in the order they appear in the file
+    pub text: Vec<TextChunk>,
+    ///Number of significant bits of every channel, stored in the sBIT chunk. Channels are in the
+    ///order of the color type, indexed images store the bits of the palette colors
+    pub significant_bits: Option<Vec<u8>>,
+    ///Image gamma
+    pub gamma: Option<Gamma>,
+    ///Chromaticities of the white point and the primaries
+    pub chromaticities: Option<Chromaticities>,
+    ///Rendering intent, only present if the image uses the sRGB color space
+    pub srgb: Option<RenderingIntent>,
+    ///Embedded ICC profile
+    pub icc_profile: Option<IccProfile>,
+    ///Color space code points, take precedence over the other color space information
+    pub cicp: Option<CodingIndependentCodePoints>,
+    ///Mastering display color volume
+    pub mastering_display: Option<MasteringDisplay>,
+    ///Physical size of the pixels
+    pub physical_dimensions: Option<PhysicalDimensions>,
+    ///Background color suggested by the author, see [`crate::Image::flatten`]
+    pub background: Option<Background>,
+    ///Raw EXIF data, stored in the eXIf chunk. Starts with a TIFF header
+    pub exif: Option<Vec<u8>>,
+    ///Time of the last modification
+    pub modification_time: Option<ModificationTime>,
+}
+
+impl Metadata {
+    ///Returns the first text entry with the given keyword
+    #[must_use]
+    pub fn get_text(&self, keyword: &str) -> Option<&str> {
+        self.text
+            .iter()
+            .find(|t| t.keyword == keyword)
+            .map(|t| t.text.as_str())
+    }
+
+    ///Returns the orientation stored in the EXIF data, `None` if there is no EXIF data or it
+    ///doesn't contain a valid orientation
+    #[must_use]
+    pub fn orientation(&self) -> Option<Orientation> {
+        let exif = self.exif.as_deref()?;
+        let (offset, big_endian) = find_orientation(exif)?;
+
+        let bytes = [exif[offset], exif[offset + 1]];
+        Orientation::from_exif(if big_endian {
+            u16::from_be_bytes(bytes)
+        } else {
+            u16::from_le_bytes(bytes)
+        })
+    }
+}
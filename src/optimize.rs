@@ -0,0 +1,367 @@
+use std::collections::HashSet;
+
+use crate::{
+    Background, CompressionLevel, Decoder, Error, FilterStrategy, Image, ImageType, IndexedImage,
+    PngEncodingOptions, Quantization, quantize, try_encode_indexed_png, try_encode_png,
+};
+
+///Settings for png optimization
+#[derive(Default, Debug, Clone, Copy)]
+pub struct OptimizationOptions {
+    ///Whether to drop text chunks. Chunks the encoder can't write are always dropped, color space
+    ///information and physical dimensions are always kept. The background color and significant
+    ///bits are kept if the color type and bit depth don't change
+    pub strip_metadata: bool,
+}
+
+///Summary of the changes made by [`optimize`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OptimizationReport {
+    ///Size of the input in bytes
+    pub original_size: usize,
+    ///Size of the output in bytes
+    pub optimized_size: usize,
+    ///Color type and bit depth of the input, as stored in the IHDR chunk
+    pub original_format: (u8, u8),
+    ///Color type and bit depth of the output
+    pub optimized_format: (u8, u8),
+    ///Number of palette entries of the output, 0 if it doesn't use a palette
+    pub palette_size: usize,
+    ///Filter strategy used for the output, `None` if the input was returned unchanged
+    pub filter: Option<FilterStrategy>,
+    ///Compression level used for the output, `None` if the input was returned unchanged
+    pub compression: Option<CompressionLevel>,
+    ///Whether the interlacing of the input was removed
+    pub removed_interlacing: bool,
+    ///Types of the ancillary chunks that were not copied to the output, in the order of the input
+    pub stripped_chunks: Vec<[u8; 4]>,
+}
+
+///Reduced form of the image to try encoding
+enum Candidate {
+    Image(Image),
+    Indexed(IndexedImage),
+}
+
+///Re-encodes a png to make it as small as possible, without changing the color of any pixel.
+///
+///The bit depth, color type and palette are reduced as far as the image allows, then every
+///filter strategy and compression level is tried and the smallest result is kept. The decoded
+///image may have a different [`ImageType`] than the original, as unused channels and precision
+///are removed. If no smaller encoding is found, or the image is animated, the input is returned
+///unchanged.
+///
+///# Errors
+///
+///May return an error if the data doesn't contain a valid png image
+///
+///# Examples
+///
+///```no_run
+///# let data: Vec<u8> = Vec::new();
+///let (png, report) = lunar_png::optimize(&data, &Default::default()).unwrap();
+///
+///println!("Saved {} bytes", report.original_size - report.optimized_size);
+///```
+#[allow(clippy::too_many_lines)]
+pub fn optimize(
+    png: &[u8],
+    options: &OptimizationOptions,
+) -> Result<(Vec<u8>, OptimizationReport), Error> {
+    let decoder = Decoder::new(png)?;
+    let animated = decoder.animation_control().is_some();
+    let (mut image, metadata) = decoder.decode()?;
+
+    //Color type and bit depth are at fixed positions of the IHDR chunk
+    let original_format = (png[25], png[24]);
+
+    let unchanged = || {
+        let report = OptimizationReport {
+            original_size: png.len(),
+            optimized_size: png.len(),
+            original_format,
+            optimized_format: original_format,
+            palette_size: 0,
+            filter: None,
+            compression: None,
+            removed_interlacing: false,
+            stripped_chunks: Vec::new(),
+        };
+
+        Ok((png.to_vec(), report))
+    };
+
+    //Frames would be lost
+    if animated {
+        return unchanged();
+    }
+
+    reduce_to_8_bit(&mut image);
+    remove_alpha(&mut image);
+    remove_color(&mut image);
+    image.reduce_bit_depth();
+
+    let mut candidates = Vec::new();
+
+    if !image.img_type.is_16_bit() && count_colors(&image) <= 256 {
+        //Fits in a palette exactly, so the conversion is lossless
+        candidates.push(Candidate::Indexed(quantize(
+            &image,
+            &Quantization::default(),
+        )));
+    }
+
+    candidates.push(Candidate::Image(image));
+
+    let text = if options.strip_metadata {
+        Vec::new()
+    } else {
+        metadata.text
+    };
+
+    //An ICC profile is redundant if the image is marked as sRGB, and the two can't be stored together
+    let icc_profile = if metadata.srgb.is_some() {
+        None
+    } else {
+        metadata.icc_profile
+    };
+
+    let mut best: Option<(Vec<u8>, FilterStrategy, CompressionLevel, usize)> = None;
+
+    for candidate in &candidates {
+        let (format, palette) = match candidate {
+            Candidate::Image(image) => (image_format(image.img_type), None),
+            Candidate::Indexed(image) => ((3, image.bit_depth), Some(&image.palette)),
+        };
+
+        //Both depend on the color type and bit depth
+        let same_format = format == original_format;
+        let significant_bits = metadata.significant_bits.clone().filter(|_| same_format);
+        let background = metadata
+            .background
+            .filter(|_| same_format)
+            .and_then(|background| match (background, palette) {
+                //The palette only contains the colors of the image
+                (Background::Rgb(color), Some(palette)) => palette
+                    .colors
+                    .iter()
+                    .any(|c| c.map(u16::from) == color)
+                    .then_some(background),
+                //Low bit depths were scaled to 8 bits by the decoder
+                (Background::Gray(value), None) if format.1 < 8 => {
+                    Some(Background::Gray(value / (255 / ((1 << format.1) - 1))))
+                }
+                _ => Some(background),
+            });
+
+        for compression in [CompressionLevel::Fast, CompressionLevel::Best] {
+            for filter in [
+                FilterStrategy::None,
+                FilterStrategy::Sub,
+                FilterStrategy::Up,
+                FilterStrategy::Average,
+                FilterStrategy::Paeth,
+                FilterStrategy::MinSum,
+                FilterStrategy::BruteForce,
+            ] {
+                let options = PngEncodingOptions {
+                    compression,
+                    filter: Some(filter),
+                    text: text.clone(),
+                    gamma: metadata.gamma,
+                    chromaticities: metadata.chromaticities,
+                    srgb: metadata.srgb,
+                    icc_profile: icc_profile.clone(),
+                    cicp: metadata.cicp,
+                    mastering_display: metadata.mastering_display,
+                    physical_dimensions: metadata.physical_dimensions,
+                    exif: metadata.exif.clone(),
+                    modification_time: metadata.modification_time,
+                    significant_bits: significant_bits.clone(),
+                    background,
+                    ..Default::default()
+                };
+
+                let (output, palette_size) = match candidate {
+                    Candidate::Image(image) => (try_encode_png(image, &options), 0),
+                    Candidate::Indexed(image) => (
+                        try_encode_indexed_png(image, &options),
+                        image.palette.colors.len(),
+                    ),
+                };
+
+                //Metadata the encoder rejects can't be kept, so the input is returned unchanged
+                let Ok(output) = output else {
+                    return unchanged();
+                };
+
+                if best.as_ref().is_none_or(|(b, ..)| output.len() < b.len()) {
+                    best = Some((output, filter, compression, palette_size));
+                }
+            }
+        }
+    }
+
+    let Some((output, filter, compression, palette_size)) = best else {
+        return unchanged();
+    };
+
+    if output.len() >= png.len() {
+        return unchanged();
+    }
+
+    let report = OptimizationReport {
+        original_size: png.len(),
+        optimized_size: output.len(),
+        original_format,
+        optimized_format: (output[25], output[24]),
+        palette_size,
+        filter: Some(filter),
+        compression: Some(compression),
+        removed_interlacing: png[28] == 1,
+        stripped_chunks: stripped_chunks(png, &output),
+    };
+
+    Ok((output, report))
+}
+
+///Returns the color type and bit depth an image is stored with
+const fn image_format(img_type: ImageType) -> (u8, u8) {
+    let color_type = match img_type.channels() {
+        1 => 0,
+        2 => 4,
+        3 => 2,
+        _ => 6,
+    };
+
+    (color_type, img_type.bit_depth())
+}
+
+///Returns the types of the ancillary chunks of the input missing from the output. Transparency is
+///part of the pixels, so tRNS chunks are ignored
+fn stripped_chunks(input: &[u8], output: &[u8]) -> Vec<[u8; 4]> {
+    let ancillary = |png: &[u8]| {
+        let mut types = Vec::new();
+        let mut offset = 8;
+
+        while let Some(header) = png.get(offset..offset + 8) {
+            let chunk_type = [header[4], header[5], header[6], header[7]];
+
+            if chunk_type[0].is_ascii_lowercase() && &chunk_type != b"tRNS" {
+                types.push(chunk_type);
+            }
+
+            offset +=
+                u32::from_be_bytes([header[0], header[1], header[2], header[3]]) as usize + 12;
+        }
+
+        types
+    };
+
+    let mut kept = ancillary(output);
+
+    ancillary(input)
+        .into_iter()
+        .filter(|chunk_type| {
+            kept.iter()
+                .position(|t| t == chunk_type)
+                .map(|i| kept.remove(i))
+                .is_none()
+        })
+        .collect()
+}
+
+///Stores 16 bit images as 8 bit if both bytes of every sample are the same
+fn reduce_to_8_bit(image: &mut Image) {
+    let img_type = match image.img_type {
+        ImageType::R16 => ImageType::R8,
+        ImageType::Ra16 => ImageType::Ra8,
+        ImageType::Rgb16 => ImageType::Rgb8,
+        ImageType::Rgba16 => ImageType::Rgba8,
+        _ => return,
+    };
+
+    if image.data.chunks(2).any(|s| s[0] != s[1]) {
+        return;
+    }
+
+    image.img_type = img_type;
+    image.data = image.data.iter().step_by(2).copied().collect();
+}
+
+///Removes the alpha channel if every pixel is fully opaque
+fn remove_alpha(image: &mut Image) {
+    let img_type = match image.img_type {
+        ImageType::Ra8 => ImageType::R8,
+        ImageType::Ra16 => ImageType::R16,
+        ImageType::Rgba8 => ImageType::Rgb8,
+        ImageType::Rgba16 => ImageType::Rgb16,
+        _ => return,
+    };
+
+    let pixel_size = image.img_type.bytes_per_pixel();
+    let color_size = img_type.bytes_per_pixel();
+
+    let opaque = image
+        .data
+        .chunks(pixel_size)
+        .all(|p| p[color_size..].iter().all(|a| *a == 0xff));
+
+    if !opaque {
+        return;
+    }
+
+    image.img_type = img_type;
+    image.data = image
+        .data
+        .chunks(pixel_size)
+        .flat_map(|p| &p[..color_size])
+        .copied()
+        .collect();
+}
+
+///Converts the image to grayscale if every pixel is gray
+fn remove_color(image: &mut Image) {
+    let img_type = match image.img_type {
+        ImageType::Rgb8 => ImageType::R8,
+        ImageType::Rgb16 => ImageType::R16,
+        ImageType::Rgba8 => ImageType::Ra8,
+        ImageType::Rgba16 => ImageType::Ra16,
+        _ => return,
+    };
+
+    let sample_size = if image.img_type.is_16_bit() { 2 } else { 1 };
+    let pixel_size = image.img_type.bytes_per_pixel();
+
+    let gray = image.data.chunks(pixel_size).all(|p| {
+        p[..sample_size] == p[sample_size..sample_size * 2]
+            && p[..sample_size] == p[sample_size * 2..sample_size * 3]
+    });
+
+    if !gray {
+        return;
+    }
+
+    image.img_type = img_type;
+    image.data = image
+        .data
+        .chunks(pixel_size)
+        .flat_map(|p| p[..sample_size].iter().chain(&p[sample_size * 3..]))
+        .copied()
+        .collect();
+}
+
+///Counts the distinct colors of the image, stopping once there are more than 256
+fn count_colors(image: &Image) -> usize {
+    let mut colors = HashSet::new();
+
+    for pixel in image.data.chunks(image.img_type.bytes_per_pixel()) {
+        colors.insert(pixel);
+
+        if colors.len() > 256 {
+            break;
+        }
+    }
+
+    colors.len()
+}
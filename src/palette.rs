@@ -0,0 +1,80 @@
+use crate::{Image, ImageType};
+
+///Color table of an indexed image, stored in the PLTE and tRNS chunks
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Palette {
+    ///Rgb colors of the palette, at most 256
+    pub colors: Vec<[u8; 3]>,
+    ///Alpha values of the first palette entries, entries past the end are fully opaque
+    pub alpha: Vec<u8>,
+}
+
+impl Palette {
+    ///Returns the color of an entry as rgba, `None` if the index is out of range
+    #[must_use]
+    pub fn get(&self, index: u8) -> Option<[u8; 4]> {
+        let [r, g, b] = *self.colors.get(index as usize)?;
+        let a = self.alpha.get(index as usize).copied().unwrap_or(255);
+
+        Some([r, g, b, a])
+    }
+
+    ///Returns the smallest bit depth able to address every entry of the palette
+    #[must_use]
+    pub const fn bit_depth(&self) -> u8 {
+        match self.colors.len() {
+            0..=2 => 1,
+            3..=4 => 2,
+            5..=16 => 4,
+            _ => 8,
+        }
+    }
+}
+
+///An image storing palette indices instead of colors
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IndexedImage {
+    ///Width of the image
+    pub width: u32,
+    ///Height of the image
+    pub height: u32,
+    ///Bit depth the indices are stored with, 1, 2, 4 or 8
+    pub bit_depth: u8,
+    ///Palette indices, one byte per pixel. Data is stored in scanlines, going left to right, top
+    ///to bottom
+    pub data: Vec<u8>,
+    ///Colors the indices refer to
+    pub palette: Palette,
+}
+
+impl IndexedImage {
+    ///Expands the indices into an `Rgba8` image if the palette has alpha values, `Rgb8` otherwise
+    ///
+    ///# Panics
+    ///
+    ///Panics if an index is outside of the palette
+    #[must_use]
+    pub fn to_image(&self) -> Image {
+        let has_alpha = !self.palette.alpha.is_empty();
+
+        let data = self
+            .data
+            .iter()
+            .flat_map(|i| {
+                let color = self.palette.get(*i).expect("Palette index out of range");
+                color.into_iter().take(if has_alpha { 4 } else { 3 })
+            })
+            .collect();
+
+        Image {
+            width: self.width,
+            height: self.height,
+            img_type: if has_alpha {
+                ImageType::Rgba8
+            } else {
+                ImageType::Rgb8
+            },
+            data,
+        }
+    }
+}
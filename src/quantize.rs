@@ -0,0 +1,236 @@
+use std::collections::HashMap;
+
+use crate::{Image, IndexedImage, Palette};
+
+///Settings for reducing an image to a palette
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Quantization {
+    ///Maximum number of palette entries, clamped to 1-256
+    pub max_colors: u16,
+    ///Whether to apply Floyd-Steinberg dithering, hiding banding at the cost of noise. Has no
+    ///effect on images that fit in the palette exactly
+    pub dithering: bool,
+}
+
+impl Default for Quantization {
+    fn default() -> Self {
+        Self {
+            max_colors: 256,
+            dithering: false,
+        }
+    }
+}
+
+///A box of colors used by median cut, with the number of pixels of each color
+type ColorBox = Vec<([u8; 4], u32)>;
+
+///Reduces an 8 bit image to at most `max_colors` colors using median cut. Images that already
+///contain few enough colors are converted losslessly. Grayscale images are converted to rgb.
+///
+///Palette entries with transparency are placed first, so the stored transparency table is as
+///short as possible, and the bit depth is the smallest one that fits the palette.
+///
+///# Panics
+///
+///Panics if the image is 16 bit or floating point, or if the length of the image data doesn't match the image type
+#[must_use]
+pub fn quantize(image: &Image, options: &Quantization) -> IndexedImage {
+    assert!(
+        image.img_type.bit_depth() <= 8,
+        "Only 8 bit images can be quantized"
+    );
+
+    let mut image = image.clone();
+    image.add_channels();
+    image.add_alpha();
+
+    let pixels: Vec<[u8; 4]> = image
+        .data
+        .chunks_exact(4)
+        .map(|p| [p[0], p[1], p[2], p[3]])
+        .collect();
+
+    let mut histogram = HashMap::new();
+    for pixel in &pixels {
+        *histogram.entry(*pixel).or_insert(0u32) += 1;
+    }
+
+    let max_colors = options.max_colors.clamp(1, 256) as usize;
+
+    //Sorted, so the output doesn't depend on the hash map order
+    let mut colors: ColorBox = histogram.into_iter().collect();
+    colors.sort_unstable();
+
+    let exact = colors.len() <= max_colors;
+
+    let mut palette: Vec<[u8; 4]> = if exact {
+        colors.into_iter().map(|(c, _)| c).collect()
+    } else {
+        median_cut(colors, max_colors)
+    };
+
+    //Transparent entries go first, so the tRNS chunk can be trimmed. Sorting by the whole color
+    //makes duplicates adjacent
+    palette.sort_unstable_by_key(|c| (c[3] == 255, *c));
+    palette.dedup();
+
+    let data = if exact {
+        let lookup: HashMap<[u8; 4], u8> = palette
+            .iter()
+            .enumerate()
+            .map(|(i, c)| (*c, i as u8))
+            .collect();
+
+        pixels.iter().map(|p| lookup[p]).collect()
+    } else if options.dithering {
+        dither(&pixels, image.width as usize, &palette)
+    } else {
+        let mut cache = HashMap::new();
+
+        pixels
+            .iter()
+            .map(|p| *cache.entry(*p).or_insert_with(|| nearest(&palette, *p)))
+            .collect()
+    };
+
+    let alpha_len = palette.iter().take_while(|c| c[3] != 255).count();
+
+    let palette = Palette {
+        colors: palette.iter().map(|c| [c[0], c[1], c[2]]).collect(),
+        alpha: palette[..alpha_len].iter().map(|c| c[3]).collect(),
+    };
+
+    IndexedImage {
+        width: image.width,
+        height: image.height,
+        bit_depth: palette.bit_depth(),
+        data,
+        palette,
+    }
+}
+
+///Splits the colors into boxes until there are `max_colors` of them, returning the average color
+///of every box
+fn median_cut(colors: ColorBox, max_colors: usize) -> Vec<[u8; 4]> {
+    let range = |b: &ColorBox, channel: usize| {
+        let (min, max) = b.iter().fold((255, 0), |(min, max), (c, _)| {
+            (c[channel].min(min), c[channel].max(max))
+        });
+
+        max - min
+    };
+
+    let widest_channel = |b: &ColorBox| (0..4).max_by_key(|c| range(b, *c)).unwrap_or(0);
+
+    let mut boxes = vec![colors];
+
+    while boxes.len() < max_colors {
+        //Split the box with the largest spread
+        let Some((index, _)) = boxes
+            .iter()
+            .enumerate()
+            .filter(|(_, b)| b.len() > 1)
+            .max_by_key(|(_, b)| range(b, widest_channel(b)))
+        else {
+            break;
+        };
+
+        let mut colors = boxes.swap_remove(index);
+        let channel = widest_channel(&colors);
+
+        colors.sort_unstable_by_key(|(c, _)| c[channel]);
+
+        //Split at the median pixel, keeping at least one color on each side
+        let total: u64 = colors.iter().map(|(_, n)| *n as u64).sum();
+        let mut count = 0;
+        let mut split = colors.len() - 1;
+
+        for (i, (_, n)) in colors.iter().enumerate() {
+            count += *n as u64;
+
+            if count * 2 >= total {
+                split = i + 1;
+                break;
+            }
+        }
+
+        let split = split.clamp(1, colors.len() - 1);
+
+        let upper = colors.split_off(split);
+        boxes.push(colors);
+        boxes.push(upper);
+    }
+
+    boxes
+        .iter()
+        .map(|b| {
+            let total: u64 = b.iter().map(|(_, n)| *n as u64).sum();
+            let mut sum = [0u64; 4];
+
+            for (color, n) in b {
+                for (s, c) in sum.iter_mut().zip(color) {
+                    *s += *c as u64 * *n as u64;
+                }
+            }
+
+            sum.map(|s| ((s + total / 2) / total) as u8)
+        })
+        .collect()
+}
+
+///Returns the index of the closest palette entry
+fn nearest(palette: &[[u8; 4]], color: [u8; 4]) -> u8 {
+    let distance = |entry: &[u8; 4]| {
+        entry
+            .iter()
+            .zip(color)
+            .map(|(a, b)| (*a as i32 - b as i32).pow(2))
+            .sum::<i32>()
+    };
+
+    palette
+        .iter()
+        .enumerate()
+        .min_by_key(|(_, entry)| distance(entry))
+        .map_or(0, |(i, _)| i as u8)
+}
+
+///Maps every pixel to the palette using Floyd-Steinberg dithering
+fn dither(pixels: &[[u8; 4]], width: usize, palette: &[[u8; 4]]) -> Vec<u8> {
+    //Errors carried to the current and the next row
+    let mut current = vec![[0i32; 4]; width + 2];
+    let mut next = vec![[0i32; 4]; width + 2];
+
+    let mut output = Vec::with_capacity(pixels.len());
+
+    for row in pixels.chunks(width) {
+        for (x, pixel) in row.iter().enumerate() {
+            //Errors are stored in 16ths, offset by one to avoid bounds checks
+            let error = current[x + 1];
+
+            let mut color = [0; 4];
+            for channel in 0..4 {
+                let value = pixel[channel] as i32 + error[channel] / 16;
+                color[channel] = value.clamp(0, 255) as u8;
+            }
+
+            let index = nearest(palette, color);
+            output.push(index);
+
+            let entry = palette[index as usize];
+
+            for channel in 0..4 {
+                let e = color[channel] as i32 - entry[channel] as i32;
+
+                current[x + 2][channel] += e * 7;
+                next[x][channel] += e * 3;
+                next[x + 1][channel] += e * 5;
+                next[x + 2][channel] += e;
+            }
+        }
+
+        current = std::mem::replace(&mut next, vec![[0; 4]; width + 2]);
+    }
+
+    output
+}
@@ -1,6 +1,9 @@
 use std::{io::Read, path::PathBuf};
 
-use crate::{decoding::Error, helpers::to_u16};
+use crate::{
+    decoding::Error,
+    helpers::{compute_crc, to_u16},
+};
 
 use super::*;
 
@@ -62,6 +65,7 @@ fn encoding() {
         &PngEncodingOptions {
             compression: CompressionLevel::None,
             write_timestamp: true,
+            ..Default::default()
         },
     );
 
@@ -84,6 +88,7 @@ fn encoding_compressed() {
         &PngEncodingOptions {
             compression: CompressionLevel::Fast,
             write_timestamp: true,
+            ..Default::default()
         },
     );
 
@@ -116,6 +121,7 @@ fn all_image_reencoding() {
             &PngEncodingOptions {
                 compression: CompressionLevel::Fast,
                 write_timestamp: false,
+                ..Default::default()
             },
         );
 
@@ -133,3 +139,2618 @@ fn all_image_reencoding() {
         }
     }
 }
+
+#[test]
+fn interlaced_decoding() {
+    let images = PathBuf::from("test-data/interlaced").read_dir().unwrap();
+
+    for i in images {
+        let file = i.unwrap().path();
+        let filename = file.file_name().unwrap().to_str().unwrap().to_string();
+
+        let Some(base) = filename.strip_suffix("_adam7.png") else {
+            continue;
+        };
+
+        println!("\nLoading {filename}");
+
+        let interlaced = std::fs::read(&file).unwrap();
+        let plain = std::fs::read(format!("test-data/interlaced/{base}.png")).unwrap();
+
+        let img = decode_png(&mut interlaced.into_iter()).unwrap();
+        let expected = decode_png(&mut plain.into_iter()).unwrap();
+
+        assert_eq!(img.img_type, expected.img_type);
+        assert_eq!(img.width, expected.width);
+        assert_eq!(img.height, expected.height);
+        assert_eq!(img.data, expected.data);
+    }
+}
+
+#[test]
+fn interlaced_reencoding() {
+    let images = PathBuf::from("test-data/basic_tests").read_dir().unwrap();
+
+    for i in images {
+        let file = i.unwrap().path();
+
+        println!("\nLoading {}", file.file_name().unwrap().to_str().unwrap());
+        let data = std::fs::read(file).unwrap();
+
+        let img = decode_png(&mut data.into_iter()).unwrap();
+
+        for compression in [CompressionLevel::None, CompressionLevel::Fast] {
+            let png = encode_png(
+                &img,
+                &PngEncodingOptions {
+                    compression,
+                    interlaced: true,
+                    ..Default::default()
+                },
+            );
+
+            //Interlace method is the last byte of IHDR
+            assert_eq!(png[28], 1);
+
+            let img1 = decode_png(&mut png.into_iter()).unwrap();
+
+            assert_eq!(img, img1);
+        }
+    }
+
+    //Small images have empty passes
+    for (width, height) in [(1, 1), (3, 2), (5, 1), (13, 11)] {
+        let img = Image {
+            width,
+            height,
+            img_type: ImageType::Rgb16,
+            data: (0..width * height * 6).map(|i| (i * 7) as u8).collect(),
+        };
+
+        let png = encode_png(
+            &img,
+            &PngEncodingOptions {
+                interlaced: true,
+                ..Default::default()
+            },
+        );
+
+        assert_eq!(decode_png(&mut png.into_iter()).unwrap(), img);
+    }
+}
+
+///Small xorshift generator, keeps the fuzzing tests reproducible
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    fn below(&mut self, n: usize) -> usize {
+        (self.next() % n as u64) as usize
+    }
+}
+
+///Recomputes the crc of every chunk, so that mutated data gets past the crc check
+fn fix_crcs(data: &mut [u8]) {
+    let mut offset = 8;
+
+    while offset + 12 <= data.len() {
+        let len = u32::from_be_bytes(data[offset..offset + 4].try_into().unwrap()) as usize;
+        let end = offset + 8 + len;
+
+        if end + 4 > data.len() {
+            break;
+        }
+
+        let crc = compute_crc(&data[offset + 4..end]);
+        data[end..end + 4].copy_from_slice(&crc.to_be_bytes());
+
+        offset = end + 4;
+    }
+}
+
+///Builds a png out of the given IHDR and chunks
+fn build_png(header: [u8; 13], chunks: &[(&[u8; 4], Vec<u8>)]) -> Vec<u8> {
+    let mut png = vec![0x89, 0x50, 0x4E, 0x47, 0x0D, 0x0A, 0x1A, 0x0A];
+
+    let header = (b"IHDR", header.to_vec());
+    let end = (b"IEND", Vec::new());
+
+    for (chunk_type, data) in std::iter::once(&header).chain(chunks).chain([&end]) {
+        let mut body = chunk_type.to_vec();
+        body.extend_from_slice(data);
+
+        png.extend_from_slice(&(data.len() as u32).to_be_bytes());
+        png.extend_from_slice(&body);
+        png.extend_from_slice(&compute_crc(&body).to_be_bytes());
+    }
+
+    png
+}
+
+///Builds a 1x1 8 bit grayscale png of a black pixel, with the given chunks before the image data
+fn gray_1x1(chunks: &[(&[u8; 4], Vec<u8>)]) -> Vec<u8> {
+    let mut chunks = chunks.to_vec();
+    chunks.push((b"IDAT", zlib(&[0, 0])));
+
+    build_png([0, 0, 0, 1, 0, 0, 0, 1, 8, 0, 0, 0, 0], &chunks)
+}
+
+fn zlib(data: &[u8]) -> Vec<u8> {
+    use std::io::Write;
+
+    let mut enc = flate2::write::ZlibEncoder::new(Vec::new(), flate2::Compression::fast());
+    enc.write_all(data).unwrap();
+    enc.finish().unwrap()
+}
+
+fn fuzz_corpus() -> Vec<Vec<u8>> {
+    ["test-data/basic_tests", "test-data/interlaced"]
+        .into_iter()
+        .flat_map(|dir| PathBuf::from(dir).read_dir().unwrap())
+        .map(|i| std::fs::read(i.unwrap().path()).unwrap())
+        .chain([test_apng(true), test_apng(false)])
+        .collect()
+}
+
+#[test]
+fn truncated_streams() {
+    for data in fuzz_corpus() {
+        for len in 0..data.len() {
+            let mut stream = data[..len].iter().copied();
+            assert!(decode_png(&mut stream).is_err());
+        }
+    }
+
+    assert_eq!(
+        decode_png(&mut std::iter::empty()),
+        Err(Error::TruncatedStream)
+    );
+}
+
+#[test]
+fn mutated_streams() {
+    let mut rng = Rng(0x2545_f491_4f6c_dd1d);
+
+    for data in fuzz_corpus() {
+        for _ in 0..200 {
+            let mut data = data.clone();
+
+            for _ in 0..=rng.below(4) {
+                let index = rng.below(data.len());
+                data[index] = rng.next() as u8;
+            }
+
+            //Leave the crc broken every once in a while
+            if rng.below(8) != 0 {
+                fix_crcs(&mut data);
+            }
+
+            //Only checking that this doesn't panic
+            let _ = decode_png(&mut data.clone().into_iter());
+            let _ = decode_apng(&mut data.into_iter());
+        }
+    }
+}
+
+#[test]
+fn malformed_images() {
+    let header = |color_type: u8, bit_depth: u8| {
+        let mut h = [0; 13];
+        h[..4].copy_from_slice(&2u32.to_be_bytes());
+        h[4..8].copy_from_slice(&2u32.to_be_bytes());
+        h[8] = bit_depth;
+        h[9] = color_type;
+        h
+    };
+
+    let png = build_png(header(5, 8), &[]);
+    assert_eq!(
+        decode_png(&mut png.into_iter()),
+        Err(Error::UnknownColorType(5))
+    );
+
+    let png = build_png(header(0, 8), &[(b"IDAT", vec![1, 2, 3, 4, 5])]);
+    assert_eq!(decode_png(&mut png.into_iter()), Err(Error::ZlibError));
+
+    let png = build_png(header(0, 8), &[(b"IDAT", zlib(&[0, 1, 2]))]);
+    assert_eq!(
+        decode_png(&mut png.into_iter()),
+        Err(Error::InvalidPngData("Not enough image data"))
+    );
+
+    let png = build_png(
+        header(3, 8),
+        &[
+            (b"PLTE", vec![0xff, 0, 0]),
+            (b"IDAT", zlib(&[0, 0, 0, 0, 0, 1])),
+        ],
+    );
+    assert_eq!(
+        decode_png(&mut png.into_iter()),
+        Err(Error::PaletteIndexOutOfRange(1))
+    );
+
+    let mut short_header = build_png(header(0, 8), &[]);
+    //Drop the last byte of the IHDR data, keeping the rest of the chunk intact
+    short_header[11] = 12;
+    short_header.remove(8 + 8 + 12);
+    fix_crcs(&mut short_header);
+    assert_eq!(
+        decode_png(&mut short_header.into_iter()),
+        Err(Error::InvalidPngData("Invalid IHDR length"))
+    );
+}
+
+#[test]
+fn unknown_chunks() {
+    let mut header = [0; 13];
+    header[..4].copy_from_slice(&1u32.to_be_bytes());
+    header[4..8].copy_from_slice(&1u32.to_be_bytes());
+    header[8] = 8;
+
+    let idat = (b"IDAT", zlib(&[0, 0x7f]));
+
+    let png = build_png(header, &[(b"vpAg", vec![1, 2, 3]), idat.clone()]);
+    let (img, metadata) = decode_png_with_metadata(&mut png.into_iter()).unwrap();
+
+    assert_eq!(img.data, [0x7f]);
+    assert_eq!(
+        metadata.unknown_chunks,
+        [UnknownChunk {
+            chunk_type: *b"vpAg",
+            data: vec![1, 2, 3],
+        }]
+    );
+
+    let chunk = &metadata.unknown_chunks[0];
+    assert!(chunk.is_ancillary());
+    assert!(chunk.is_private());
+    assert!(chunk.is_safe_to_copy());
+
+    let png = build_png(header, &[(b"ABCD", Vec::new()), idat.clone()]);
+    assert_eq!(
+        decode_png(&mut png.into_iter()),
+        Err(Error::UnknownCriticalChunk(*b"ABCD"))
+    );
+
+    let png = build_png(header, &[(b"ab1d", Vec::new()), idat]);
+    assert_eq!(
+        decode_png(&mut png.into_iter()),
+        Err(Error::InvalidChunkType)
+    );
+}
+
+///Reader that returns a single byte per read call
+struct ByteReader(std::vec::IntoIter<u8>);
+
+impl Read for ByteReader {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        match (buf.first_mut(), self.0.next()) {
+            (Some(b), Some(v)) => {
+                *b = v;
+                Ok(1)
+            }
+            _ => Ok(0),
+        }
+    }
+}
+
+#[test]
+fn streaming_decoding() {
+    for data in fuzz_corpus() {
+        let img = decode_png(&mut data.clone().into_iter()).unwrap();
+
+        let mut decoder = Decoder::new(ByteReader(data.into_iter())).unwrap();
+
+        assert_eq!(decoder.width(), img.width);
+        assert_eq!(decoder.height(), img.height);
+        assert_eq!(decoder.img_type(), img.img_type);
+
+        let row_size = img.width as usize * img.img_type.bytes_per_pixel();
+        let mut rows = img.data.chunks(row_size);
+
+        while let Some(row) = decoder.next_row().unwrap() {
+            assert_eq!(Some(row), rows.next());
+        }
+        assert_eq!(rows.next(), None);
+    }
+
+    //Decoding after reading rows returns the remaining ones
+    let img = Image {
+        width: 4,
+        height: 4,
+        img_type: ImageType::R8,
+        data: (0..16).collect(),
+    };
+    let png = encode_png(&img, &PngEncodingOptions::default());
+
+    let mut decoder = Decoder::new(png.as_slice()).unwrap();
+    decoder.next_row().unwrap();
+    let (rest, _) = decoder.decode().unwrap();
+    assert_eq!(rest.height, 3);
+    assert_eq!(rest.data, img.data[4..]);
+}
+
+#[test]
+fn streaming_partial_data() {
+    let mut rng = Rng(0x9e37_79b9_7f4a_7c15);
+
+    //Noise doesn't compress, so the image data spans a lot of input
+    let img = Image {
+        width: 256,
+        height: 256,
+        img_type: ImageType::Rgb8,
+        data: (0..256 * 256 * 3).map(|_| rng.next() as u8).collect(),
+    };
+
+    let png = encode_png(&img, &PngEncodingOptions::default());
+
+    //Rows at the start of the image are available before the rest of the file is read
+    let truncated = &png[..png.len() / 2];
+    let mut decoder = Decoder::new(truncated).unwrap();
+
+    assert_eq!(decoder.next_row().unwrap(), Some(&img.data[..256 * 3]));
+
+    let mut result = Ok(None);
+    for _ in 0..256 {
+        result = decoder.next_row().map(|r| r.map(<[u8]>::to_vec));
+        if result.is_err() {
+            break;
+        }
+    }
+    assert_eq!(result, Err(Error::TruncatedStream));
+}
+
+#[test]
+fn streaming_encoding() {
+    let mut rng = Rng(0xdead_beef_cafe_f00d);
+
+    let img = Image {
+        width: 300,
+        height: 200,
+        img_type: ImageType::Rgba16,
+        data: (0..300 * 200 * 8).map(|_| rng.next() as u8).collect(),
+    };
+    let row_size = 300 * 8;
+
+    for interlaced in [false, true] {
+        let options = PngEncodingOptions {
+            interlaced,
+            ..Default::default()
+        };
+
+        let mut encoder = Encoder::new(Vec::new(), 300, 200, ImageType::Rgba16, &options).unwrap();
+
+        for row in img.data.chunks(row_size) {
+            encoder.write_row(row).unwrap();
+        }
+
+        let png = encoder.finish().unwrap();
+
+        //Noise doesn't compress, so the data has to be split into multiple IDAT chunks
+        let mut offset = 8;
+        let mut idat_count = 0;
+        while offset < png.len() {
+            let len = u32::from_be_bytes(png[offset..offset + 4].try_into().unwrap()) as usize;
+
+            if &png[offset + 4..offset + 8] == b"IDAT" {
+                assert!(len <= 64 * 1024);
+                idat_count += 1;
+            }
+
+            offset += len + 12;
+        }
+        assert!(idat_count > 1);
+
+        assert_eq!(decode_png(&mut png.clone().into_iter()).unwrap(), img);
+        assert_eq!(png, encode_png(&img, &options));
+    }
+
+    let options = PngEncodingOptions::default();
+
+    let mut encoder = Encoder::new(Vec::new(), 2, 2, ImageType::R8, &options).unwrap();
+    assert!(encoder.write_row(&[0; 3]).is_err());
+    assert!(encoder.write_rows(&[0; 3]).is_err());
+    encoder.write_rows(&[0; 2]).unwrap();
+    assert!(encoder.finish().is_err());
+
+    let mut encoder = Encoder::new(Vec::new(), 2, 2, ImageType::R8, &options).unwrap();
+    encoder.write_rows(&[0; 4]).unwrap();
+    assert!(encoder.write_row(&[0; 2]).is_err());
+    assert!(encoder.finish().is_ok());
+
+    assert!(Encoder::new(Vec::new(), 0, 2, ImageType::R8, &options).is_err());
+
+    assert_eq!(
+        try_encode_png(&img, &options).unwrap(),
+        encode_png(&img, &options)
+    );
+
+    let short = Image {
+        data: vec![0; 3],
+        ..img.clone()
+    };
+    assert!(try_encode_png(&short, &options).is_err());
+
+    let bad_keyword = PngEncodingOptions {
+        text: vec![TextChunk::new("", "text")],
+        ..Default::default()
+    };
+    assert!(try_encode_png(&img, &bad_keyword).is_err());
+
+    //NUL separates the fields of text chunks
+    let mut international = TextChunk::new("Title", "text");
+    international.international = Some(InternationalText {
+        language_tag: "en\0".into(),
+        translated_keyword: String::new(),
+    });
+    for text in [TextChunk::new("Au\0thor", "x"), international] {
+        let options = PngEncodingOptions {
+            text: vec![text],
+            ..Default::default()
+        };
+        assert!(try_encode_png(&img, &options).is_err());
+    }
+
+    let palette = Palette {
+        colors: vec![[0; 3]],
+        alpha: Vec::new(),
+    };
+    let indexed = IndexedImage {
+        width: 1,
+        height: 1,
+        bit_depth: 1,
+        palette,
+        data: vec![1],
+    };
+    assert!(try_encode_indexed_png(&indexed, &options).is_err());
+}
+
+///Returns the types of every chunk in a png
+fn chunk_types(png: &[u8]) -> Vec<[u8; 4]> {
+    let mut offset = 8;
+    let mut types = Vec::new();
+
+    while offset < png.len() {
+        let len = u32::from_be_bytes(png[offset..offset + 4].try_into().unwrap()) as usize;
+        types.push(png[offset + 4..offset + 8].try_into().unwrap());
+        offset += len + 12;
+    }
+
+    types
+}
+
+#[test]
+fn text_chunks() {
+    let img = Image {
+        width: 4,
+        height: 4,
+        img_type: ImageType::Rgb8,
+        data: (0..48).collect(),
+    };
+
+    let text = vec![
+        TextChunk::new("Author", "Somebody Ünusual"),
+        TextChunk {
+            compressed: true,
+            ..TextChunk::new("Description", "a".repeat(1000))
+        },
+        TextChunk {
+            international: Some(InternationalText {
+                language_tag: "ja".into(),
+                translated_keyword: "ソフトウェア".into(),
+            }),
+            compressed: true,
+            ..TextChunk::new("Software", "ルナー")
+        },
+        TextChunk {
+            international: Some(InternationalText::default()),
+            ..TextChunk::new("Source", "abc")
+        },
+    ];
+
+    let png = encode_png(
+        &img,
+        &PngEncodingOptions {
+            text: text.clone(),
+            ..Default::default()
+        },
+    );
+
+    let types = chunk_types(&png);
+    assert_eq!(types.iter().filter(|t| *t == b"tEXt").count(), 1);
+    assert_eq!(types.iter().filter(|t| *t == b"zTXt").count(), 1);
+    assert_eq!(types.iter().filter(|t| *t == b"iTXt").count(), 2);
+
+    let (img1, metadata) = decode_png_with_metadata(&mut png.into_iter()).unwrap();
+
+    assert_eq!(img, img1);
+    assert_eq!(metadata.text, text);
+    assert_eq!(metadata.get_text("Software"), Some("ルナー"));
+    assert_eq!(metadata.get_text("Title"), None);
+
+    //Text outside of latin-1 has to be stored as iTXt
+    let png = encode_png(
+        &img,
+        &PngEncodingOptions {
+            text: vec![TextChunk::new("Title", "🌙")],
+            ..Default::default()
+        },
+    );
+    assert!(chunk_types(&png).contains(b"iTXt"));
+
+    let (_, metadata) = decode_png_with_metadata(&mut png.into_iter()).unwrap();
+    assert_eq!(metadata.get_text("Title"), Some("🌙"));
+
+    let mut encoder = Encoder::new(
+        Vec::new(),
+        4,
+        4,
+        ImageType::Rgb8,
+        &PngEncodingOptions {
+            text: vec![TextChunk::new("", "empty keyword")],
+            ..Default::default()
+        },
+    );
+    assert!(encoder.is_err());
+
+    encoder = Encoder::new(
+        Vec::new(),
+        4,
+        4,
+        ImageType::Rgb8,
+        &PngEncodingOptions {
+            text: vec![TextChunk::new("🌙", "keyword outside of latin-1")],
+            ..Default::default()
+        },
+    );
+    assert!(encoder.is_err());
+
+    //Malformed text chunks are skipped
+    let png = gray_1x1(&[
+        (b"tEXt", b"no separator".to_vec()),
+        (b"zTXt", b"Title\0\0not zlib".to_vec()),
+        (b"iTXt", b"Title\0\x02\0\0\0".to_vec()),
+        (b"tEXt", b"Title\0text".to_vec()),
+    ]);
+
+    let (_, metadata) = decode_png_with_metadata(&mut png.into_iter()).unwrap();
+    assert_eq!(metadata.text, [TextChunk::new("Title", "text")]);
+}
+
+///Adds a None filter type byte in front of every row
+fn unfiltered_rows(data: &[u8], row_size: usize) -> Vec<u8> {
+    data.chunks(row_size)
+        .flat_map(|row| std::iter::once(0).chain(row.iter().copied()))
+        .collect()
+}
+
+fn frame_control(sequence: u32, size: (u32, u32), offset: (u32, u32), ops: (u8, u8)) -> Vec<u8> {
+    [sequence, size.0, size.1, offset.0, offset.1]
+        .iter()
+        .flat_map(|i| i.to_be_bytes())
+        .chain([0, 1, 0, 10, ops.0, ops.1])
+        .collect()
+}
+
+fn frame_data(sequence: u32, data: &[u8], row_size: usize) -> Vec<u8> {
+    let mut o = sequence.to_be_bytes().to_vec();
+    o.append(&mut zlib(&unfiltered_rows(data, row_size)));
+    o
+}
+
+///A 4x4 animation with 3 frames
+fn test_apng(default_is_frame: bool) -> Vec<u8> {
+    let mut header = [0; 13];
+    header[..4].copy_from_slice(&4u32.to_be_bytes());
+    header[4..8].copy_from_slice(&4u32.to_be_bytes());
+    header[8] = 8;
+    header[9] = 6;
+
+    let red = [255u8, 0, 0, 255].repeat(16);
+    let blue = [0u8, 0, 255, 128].repeat(4);
+    let green = [0u8, 255, 0, 255];
+
+    let mut chunks = vec![(b"acTL", vec![0, 0, 0, 3, 0, 0, 0, 2])];
+
+    if default_is_frame {
+        chunks.push((b"fcTL", frame_control(0, (4, 4), (0, 0), (0, 0))));
+        chunks.push((b"IDAT", zlib(&unfiltered_rows(&red, 16))));
+    } else {
+        chunks.push((b"IDAT", zlib(&unfiltered_rows(&[0; 64], 16))));
+        chunks.push((b"fcTL", frame_control(0, (4, 4), (0, 0), (0, 0))));
+        chunks.push((b"fdAT", frame_data(1, &red, 16)));
+    }
+
+    chunks.extend([
+        (b"fcTL", frame_control(2, (2, 2), (1, 1), (1, 1))),
+        (b"fdAT", frame_data(3, &blue[..8], 8)),
+        (b"fdAT", frame_data(4, &blue[8..], 8)),
+        (b"fcTL", frame_control(5, (1, 1), (3, 3), (2, 0))),
+        (b"fdAT", frame_data(6, &green, 4)),
+    ]);
+
+    //The fdAT chunks of a frame are a single zlib stream
+    let split = chunks
+        .iter()
+        .position(|c| c.0 == b"fdAT" && c.1[3] == 3)
+        .unwrap();
+    let stream = zlib(&unfiltered_rows(&blue, 8));
+    let (a, b) = stream.split_at(stream.len() / 2);
+    chunks[split].1 = [&3u32.to_be_bytes(), a].concat();
+    chunks[split + 1].1 = [&4u32.to_be_bytes(), b].concat();
+
+    build_png(header, &chunks)
+}
+
+#[test]
+fn apng_decoding() {
+    for default_is_frame in [true, false] {
+        let png = test_apng(default_is_frame);
+
+        let animation = decode_apng(&mut png.clone().into_iter()).unwrap();
+
+        assert_eq!(animation.num_plays, 2);
+        assert_eq!(animation.frames.len(), 3);
+        assert_eq!(animation.default_image.is_none(), default_is_frame);
+
+        let control = animation.frames[1].control;
+        assert_eq!((control.width, control.height), (2, 2));
+        assert_eq!((control.x_offset, control.y_offset), (1, 1));
+        assert_eq!(control.dispose_op, DisposeOp::Background);
+        assert_eq!(control.blend_op, BlendOp::Over);
+        assert_eq!(control.delay(), std::time::Duration::from_millis(100));
+
+        assert_eq!(animation.frames[1].image.data, [0, 0, 255, 128].repeat(4));
+        assert_eq!(animation.frames[2].control.dispose_op, DisposeOp::Previous);
+
+        let canvases = animation.composite(ByteOrder::LittleEndian);
+        assert_eq!(canvases.len(), 3);
+
+        let pixel = |canvas: &Image, x: usize, y: usize| {
+            let i = (y * 4 + x) * 4;
+            canvas.data[i..i + 4].to_vec()
+        };
+
+        assert!(canvases[0].data.chunks(4).all(|p| p == [255, 0, 0, 255]));
+
+        assert_eq!(pixel(&canvases[1], 0, 0), [255, 0, 0, 255]);
+        assert_eq!(pixel(&canvases[1], 1, 1), [127, 0, 128, 255]);
+        assert_eq!(pixel(&canvases[1], 2, 2), [127, 0, 128, 255]);
+
+        //The previous frame area was cleared
+        assert_eq!(pixel(&canvases[2], 1, 1), [0, 0, 0, 0]);
+        assert_eq!(pixel(&canvases[2], 3, 1), [255, 0, 0, 255]);
+        assert_eq!(pixel(&canvases[2], 3, 3), [0, 255, 0, 255]);
+
+        //Regular decoding only returns the default image
+        let img = decode_png(&mut png.clone().into_iter()).unwrap();
+        assert_eq!(img.width, 4);
+        assert_eq!(img.img_type, ImageType::Rgba8);
+
+        for len in 0..png.len() {
+            assert!(decode_apng(&mut png[..len].iter().copied()).is_err());
+        }
+    }
+
+    //Static images are a single frame
+    let png = encode_png(
+        &Image {
+            width: 2,
+            height: 1,
+            img_type: ImageType::R8,
+            data: vec![1, 2],
+        },
+        &PngEncodingOptions::default(),
+    );
+
+    let animation = decode_apng(&mut png.into_iter()).unwrap();
+    assert_eq!(animation.frames.len(), 1);
+    assert_eq!(animation.frames[0].image.data, [1, 2]);
+    assert_eq!(
+        animation.composite(ByteOrder::LittleEndian)[0].data,
+        [1, 1, 1, 255, 2, 2, 2, 255]
+    );
+}
+
+#[test]
+fn apng_sequence_numbers() {
+    let mut png = test_apng(true);
+
+    //Swap the sequence numbers of the last fcTL and fdAT chunks
+    let fdat = png.windows(4).rposition(|w| w == b"fdAT").unwrap();
+    let fctl = png.windows(4).rposition(|w| w == b"fcTL").unwrap();
+    png[fdat + 7] = 5;
+    png[fctl + 7] = 6;
+    fix_crcs(&mut png);
+
+    assert_eq!(
+        decode_apng(&mut png.into_iter()),
+        Err(Error::InvalidPngData("Invalid APNG sequence number"))
+    );
+}
+
+#[test]
+fn apng_frame_regions() {
+    //Overwrites a field of the fcTL data of the first or last frame
+    let modified = |last: bool, field: usize, value: u32| {
+        let mut png = test_apng(true);
+        let fctl = if last {
+            png.windows(4).rposition(|w| w == b"fcTL")
+        } else {
+            png.windows(4).position(|w| w == b"fcTL")
+        }
+        .unwrap();
+
+        let offset = fctl + 4 + field * 4;
+        png[offset..offset + 4].copy_from_slice(&value.to_be_bytes());
+        fix_crcs(&mut png);
+        png
+    };
+
+    let first_frame = Err(Error::InvalidPngData(
+        "First frame has to cover the whole canvas",
+    ));
+
+    //Width, height and offsets of the default image have to match the canvas
+    for (field, value) in [(1, 2), (2, 3), (3, 1), (4, 1)] {
+        let png = modified(false, field, value);
+        assert_eq!(decode_apng(&mut png.into_iter()), first_frame);
+    }
+
+    //Other frames have to fit on the canvas
+    for (field, value) in [(1, 4), (3, 4), (4, u32::MAX)] {
+        let png = modified(true, field, value);
+        assert_eq!(
+            decode_apng(&mut png.into_iter()),
+            Err(Error::InvalidPngData("Frame is outside of the canvas"))
+        );
+    }
+}
+
+#[test]
+fn apng_encoding() {
+    for default_is_frame in [true, false] {
+        let animation = decode_apng(&mut test_apng(default_is_frame).into_iter()).unwrap();
+
+        for interlaced in [false, true] {
+            let options = PngEncodingOptions {
+                interlaced,
+                ..Default::default()
+            };
+
+            let png = encode_apng(&animation, &options).unwrap();
+            let types = chunk_types(&png);
+            assert_eq!(types[1], *b"acTL");
+            assert_eq!(types.iter().filter(|t| *t == b"fcTL").count(), 3);
+
+            assert_eq!(decode_apng(&mut png.into_iter()).unwrap(), animation);
+        }
+    }
+
+    //Large frames are split into several fdAT chunks
+    let mut rng = Rng(0x1234_5678);
+    let frame = |rng: &mut Rng| Frame {
+        control: FrameControl {
+            width: 200,
+            height: 200,
+            x_offset: 0,
+            y_offset: 0,
+            delay_num: 1,
+            delay_den: 30,
+            dispose_op: DisposeOp::None,
+            blend_op: BlendOp::Source,
+        },
+        image: Image {
+            width: 200,
+            height: 200,
+            img_type: ImageType::Rgba8,
+            data: (0..200 * 200 * 4).map(|_| rng.next() as u8).collect(),
+        },
+    };
+
+    let animation = Animation {
+        width: 200,
+        height: 200,
+        num_plays: 0,
+        frames: vec![frame(&mut rng), frame(&mut rng), frame(&mut rng)],
+        default_image: None,
+    };
+
+    let png = encode_apng(&animation, &PngEncodingOptions::default()).unwrap();
+    assert!(chunk_types(&png).iter().filter(|t| *t == b"fdAT").count() > 2);
+    assert_eq!(decode_apng(&mut png.into_iter()).unwrap(), animation);
+}
+
+#[test]
+fn apng_cropping() {
+    for default_is_frame in [true, false] {
+        let animation = decode_apng(&mut test_apng(default_is_frame).into_iter()).unwrap();
+
+        let options = PngEncodingOptions {
+            crop_frames: true,
+            ..Default::default()
+        };
+
+        let cropped =
+            decode_apng(&mut encode_apng(&animation, &options).unwrap().into_iter()).unwrap();
+
+        assert_eq!(
+            cropped.composite(ByteOrder::LittleEndian),
+            animation.composite(ByteOrder::LittleEndian)
+        );
+        assert_eq!(cropped.default_image.is_none(), default_is_frame);
+
+        let control = cropped.frames[1].control;
+        assert_eq!((control.width, control.height), (2, 2));
+        assert_eq!((control.x_offset, control.y_offset), (1, 1));
+        assert_eq!(control.delay(), std::time::Duration::from_millis(100));
+    }
+
+    //Frames without changes are reduced to a single pixel
+    let image = Image {
+        width: 3,
+        height: 3,
+        img_type: ImageType::Rgba8,
+        data: [10, 20, 30, 255].repeat(9),
+    };
+    let frame = Frame {
+        control: FrameControl {
+            width: 3,
+            height: 3,
+            x_offset: 0,
+            y_offset: 0,
+            delay_num: 0,
+            delay_den: 0,
+            dispose_op: DisposeOp::None,
+            blend_op: BlendOp::Source,
+        },
+        image,
+    };
+    let animation = Animation {
+        width: 3,
+        height: 3,
+        num_plays: 1,
+        frames: vec![frame.clone(), frame.clone()],
+        default_image: None,
+    };
+
+    let options = PngEncodingOptions {
+        crop_frames: true,
+        ..Default::default()
+    };
+    let cropped = decode_apng(&mut encode_apng(&animation, &options).unwrap().into_iter()).unwrap();
+
+    assert_eq!(cropped.frames[1].image.width, 1);
+    assert_eq!(cropped.frames[1].image.height, 1);
+    assert_eq!(
+        cropped.composite(ByteOrder::LittleEndian),
+        animation.composite(ByteOrder::LittleEndian)
+    );
+
+    //Cropped frames are stored as rgba, including frames of rgb animations
+    let rgb = |data: &[u8]| Frame {
+        control: FrameControl {
+            width: 2,
+            height: 2,
+            ..frame.control
+        },
+        image: Image {
+            width: 2,
+            height: 2,
+            img_type: ImageType::Rgb8,
+            data: data.repeat(4),
+        },
+    };
+    let default_image = Image {
+        width: 2,
+        height: 2,
+        img_type: ImageType::Rgb8,
+        data: [1, 2, 3].repeat(4),
+    };
+
+    for default_image in [None, Some(default_image)] {
+        let animation = Animation {
+            width: 2,
+            height: 2,
+            num_plays: 0,
+            frames: vec![rgb(&[255, 0, 0]), rgb(&[0, 255, 0])],
+            default_image,
+        };
+
+        let png = encode_apng(&animation, &options).unwrap();
+        let cropped = decode_apng(&mut png.into_iter()).unwrap();
+
+        assert_eq!(cropped.frames[0].image.img_type, ImageType::Rgba8);
+        assert_eq!(
+            cropped.composite(ByteOrder::LittleEndian),
+            animation.composite(ByteOrder::LittleEndian)
+        );
+        assert_eq!(
+            cropped.default_image.map(|i| i.data),
+            animation.default_image.map(|mut i| {
+                i.add_alpha();
+                i.data
+            })
+        );
+    }
+}
+
+#[test]
+fn apng_encoding_errors() {
+    let animation = decode_apng(&mut test_apng(true).into_iter()).unwrap();
+    let options = PngEncodingOptions::default();
+
+    let mut empty = animation.clone();
+    empty.frames.clear();
+    assert!(encode_apng(&empty, &options).is_err());
+
+    let mut off_canvas = animation.clone();
+    off_canvas.frames[1].control.x_offset = 3;
+    assert!(encode_apng(&off_canvas, &options).is_err());
+
+    let mut wrong_size = animation.clone();
+    wrong_size.frames[1].control.width = 1;
+    assert!(encode_apng(&wrong_size, &options).is_err());
+
+    let mut partial_first = animation.clone();
+    partial_first.frames.remove(0);
+    assert!(encode_apng(&partial_first, &options).is_err());
+
+    let mut mixed_types = animation;
+    let image = &mut mixed_types.frames[1].image;
+    image.img_type = ImageType::Rgb8;
+    image.data.truncate(12);
+    assert!(encode_apng(&mixed_types, &options).is_err());
+}
+
+#[test]
+fn indexed_images() {
+    for dir in ["test-data/basic_tests", "test-data/interlaced"] {
+        for i in PathBuf::from(dir).read_dir().unwrap() {
+            let file = i.unwrap().path();
+            let filename = file.file_name().unwrap().to_str().unwrap().to_string();
+
+            let data = std::fs::read(&file).unwrap();
+
+            if !filename.contains("indexed") {
+                assert_eq!(
+                    decode_indexed_png(&mut data.into_iter()),
+                    Err(Error::NotIndexed)
+                );
+                continue;
+            }
+
+            println!("\nLoading {filename}");
+
+            let indexed = decode_indexed_png(&mut data.clone().into_iter()).unwrap();
+            let expected = decode_png(&mut data.into_iter()).unwrap();
+
+            let bit_depth: u8 = filename[..1].parse().unwrap();
+            assert_eq!(indexed.bit_depth, bit_depth);
+            assert_eq!(indexed.to_image(), expected);
+
+            for interlaced in [false, true] {
+                let options = PngEncodingOptions {
+                    interlaced,
+                    ..Default::default()
+                };
+
+                let png = encode_indexed_png(&indexed, &options);
+
+                //Bit depth and color type are kept
+                assert_eq!(png[24..26], [bit_depth, 3]);
+                assert_eq!(decode_indexed_png(&mut png.into_iter()).unwrap(), indexed);
+            }
+        }
+    }
+
+    let palette = Palette {
+        colors: vec![[255, 0, 0], [0, 255, 0], [0, 0, 255]],
+        alpha: vec![0],
+    };
+    assert_eq!(palette.bit_depth(), 2);
+    assert_eq!(palette.get(0), Some([255, 0, 0, 0]));
+    assert_eq!(palette.get(2), Some([0, 0, 255, 255]));
+    assert_eq!(palette.get(3), None);
+
+    let image = IndexedImage {
+        width: 5,
+        height: 1,
+        bit_depth: 2,
+        data: vec![0, 1, 2, 1, 0],
+        palette: palette.clone(),
+    };
+
+    let png = encode_indexed_png(&image, &PngEncodingOptions::default());
+    assert!(chunk_types(&png).contains(b"tRNS"));
+    assert_eq!(
+        decode_png(&mut png.into_iter()).unwrap().data,
+        [
+            255, 0, 0, 0, 0, 255, 0, 255, 0, 0, 255, 255, 0, 255, 0, 255, 255, 0, 0, 0
+        ]
+    );
+
+    let options = PngEncodingOptions::default();
+
+    //Indices outside of the palette
+    let mut encoder = Encoder::new_indexed(Vec::new(), 1, 1, 2, &palette, &options).unwrap();
+    assert!(encoder.write_row(&[3]).is_err());
+
+    //Palettes that don't fit the bit depth
+    assert!(Encoder::new_indexed(Vec::new(), 1, 1, 1, &palette, &options).is_err());
+    assert!(Encoder::new_indexed(Vec::new(), 1, 1, 3, &palette, &options).is_err());
+    assert!(Encoder::new_indexed(Vec::new(), 1, 1, 8, &Palette::default(), &options).is_err());
+
+    //Transparency entries past the end of the palette are dropped
+    let png = build_png(
+        [0, 0, 0, 1, 0, 0, 0, 1, 8, 3, 0, 0, 0],
+        &[
+            (b"PLTE", vec![255, 0, 0]),
+            (b"tRNS", vec![128, 0, 0]),
+            (b"IDAT", zlib(&[0, 0])),
+        ],
+    );
+    let indexed = decode_indexed_png(&mut png.into_iter()).unwrap();
+    assert_eq!(indexed.palette.alpha, [128]);
+
+    let png = encode_indexed_png(&indexed, &options);
+    assert_eq!(decode_indexed_png(&mut png.into_iter()).unwrap(), indexed);
+}
+
+#[test]
+fn quantization() {
+    let bit_depth = |png: &[u8]| (png[24], png[25]);
+
+    //Images with few colors are converted losslessly
+    let image = Image {
+        width: 4,
+        height: 2,
+        img_type: ImageType::Rgba8,
+        data: [
+            [255, 0, 0, 255],
+            [0, 255, 0, 255],
+            [0, 0, 255, 128],
+            [0, 0, 0, 0],
+        ]
+        .repeat(2)
+        .concat(),
+    };
+
+    let indexed = quantize(&image, &Quantization::default());
+    assert_eq!(indexed.bit_depth, 2);
+    assert_eq!(indexed.palette.colors.len(), 4);
+    //Only the transparent entries are stored in tRNS
+    assert_eq!(indexed.palette.alpha.len(), 2);
+    assert_eq!(indexed.to_image(), image);
+
+    let options = PngEncodingOptions {
+        quantize: Some(Quantization::default()),
+        ..Default::default()
+    };
+
+    let png = encode_png(&image, &options);
+    assert_eq!(bit_depth(&png), (2, 3));
+    assert_eq!(decode_png(&mut png.into_iter()).unwrap(), image);
+
+    let image = Image {
+        width: 3,
+        height: 1,
+        img_type: ImageType::R8,
+        data: vec![0, 255, 0],
+    };
+    let png = encode_png(&image, &options);
+    assert_eq!(bit_depth(&png), (1, 3));
+    assert!(!chunk_types(&png).contains(b"tRNS"));
+
+    //16 bit images are left unchanged
+    let image = Image {
+        width: 1,
+        height: 1,
+        img_type: ImageType::R16,
+        data: vec![1, 2],
+    };
+    assert_eq!(bit_depth(&encode_png(&image, &options)), (16, 0));
+
+    //Gradients have to be reduced
+    let mut data = Vec::new();
+    for y in 0..64u32 {
+        for x in 0..64u32 {
+            data.extend_from_slice(&[(x * 4) as u8, (y * 4) as u8, ((x + y) * 2) as u8]);
+        }
+    }
+
+    let image = Image {
+        width: 64,
+        height: 64,
+        img_type: ImageType::Rgb8,
+        data,
+    };
+
+    for dithering in [false, true] {
+        for (max_colors, max_error) in [(2, 48), (16, 20), (200, 6)] {
+            let quantization = Quantization {
+                max_colors,
+                dithering,
+            };
+            let indexed = quantize(&image, &quantization);
+
+            assert!(indexed.palette.colors.len() <= max_colors as usize);
+            assert!(indexed.palette.alpha.is_empty());
+            assert_eq!(indexed.bit_depth, indexed.palette.bit_depth());
+
+            let output = indexed.to_image();
+            assert_eq!(output.img_type, ImageType::Rgb8);
+
+            //The average error shrinks as the palette grows
+            let error = output
+                .data
+                .iter()
+                .zip(&image.data)
+                .map(|(a, b)| a.abs_diff(*b) as u64)
+                .sum::<u64>()
+                / image.data.len() as u64;
+
+            assert!(error < max_error, "{error}");
+
+            let png = encode_png(
+                &image,
+                &PngEncodingOptions {
+                    quantize: Some(quantization),
+                    ..Default::default()
+                },
+            );
+            assert_eq!(decode_png(&mut png.into_iter()).unwrap(), output);
+        }
+    }
+
+    //Median cut can produce the same color for boxes that aren't next to each other
+    let image = Image {
+        width: 14,
+        height: 1,
+        img_type: ImageType::Rgba8,
+        data: vec![
+            0, 2, 2, 1, 2, 1, 2, 1, 1, 0, 0, 1, 2, 1, 1, 2, 2, 0, 2, 2, 1, 2, 1, 2, 1, 2, 1, 0, 1,
+            1, 2, 2, 1, 0, 1, 0, 2, 0, 1, 1, 0, 0, 1, 0, 1, 0, 1, 1, 2, 2, 2, 1, 1, 1, 0, 2,
+        ],
+    };
+    let quantization = Quantization {
+        max_colors: 5,
+        dithering: false,
+    };
+    let palette = quantize(&image, &quantization).palette;
+
+    let mut entries: Vec<_> = (0..palette.colors.len())
+        .map(|i| {
+            (
+                palette.colors[i],
+                palette.alpha.get(i).copied().unwrap_or(255),
+            )
+        })
+        .collect();
+    let len = entries.len();
+    entries.sort_unstable();
+    entries.dedup();
+    assert_eq!(entries.len(), len);
+
+    //The data length is checked before quantizing
+    let image = Image {
+        width: 2,
+        height: 2,
+        img_type: ImageType::Rgb8,
+        data: vec![0; 4],
+    };
+    let options = PngEncodingOptions {
+        quantize: Some(Quantization::default()),
+        ..Default::default()
+    };
+    assert!(try_encode_png(&image, &options).is_err());
+}
+
+///Decompresses the contents of every IDAT chunk
+fn inflated_idat(png: &[u8]) -> Vec<u8> {
+    let mut compressed = Vec::new();
+    let mut pos = 8;
+
+    while pos < png.len() {
+        let length = u32::from_be_bytes(png[pos..pos + 4].try_into().unwrap()) as usize;
+
+        if &png[pos + 4..pos + 8] == b"IDAT" {
+            compressed.extend_from_slice(&png[pos + 8..pos + 8 + length]);
+        }
+
+        pos += length + 12;
+    }
+
+    let mut data = Vec::new();
+    flate2::read::ZlibDecoder::new(&compressed[..])
+        .read_to_end(&mut data)
+        .unwrap();
+
+    data
+}
+
+#[test]
+fn filter_strategies() {
+    let strategies = [
+        (FilterStrategy::None, Some(0)),
+        (FilterStrategy::Sub, Some(1)),
+        (FilterStrategy::Up, Some(2)),
+        (FilterStrategy::Average, Some(3)),
+        (FilterStrategy::Paeth, Some(4)),
+        (FilterStrategy::MinSum, None),
+        (FilterStrategy::BruteForce, None),
+    ];
+
+    for i in PathBuf::from("test-data/basic_tests").read_dir().unwrap() {
+        let file = i.unwrap().path();
+
+        println!("\nLoading {}", file.file_name().unwrap().to_str().unwrap());
+        let data = std::fs::read(file).unwrap();
+
+        let img = decode_png(&mut data.into_iter()).unwrap();
+        let row_size = img.width as usize * img.img_type.bytes_per_pixel();
+
+        for (strategy, filter_type) in strategies {
+            for interlaced in [false, true] {
+                let png = encode_png(
+                    &img,
+                    &PngEncodingOptions {
+                        filter: Some(strategy),
+                        interlaced,
+                        ..Default::default()
+                    },
+                );
+
+                if let Some(filter_type) = filter_type
+                    && !interlaced
+                {
+                    let idat = inflated_idat(&png);
+                    assert!(idat.chunks(row_size + 1).all(|row| row[0] == filter_type));
+                }
+
+                assert_eq!(decode_png(&mut png.into_iter()).unwrap(), img);
+            }
+        }
+    }
+
+    //Adaptive strategies pick different filters for different rows
+    let mut data = vec![0; 64 * 3];
+    data.extend((0..64 * 3).map(|i| i as u8));
+    data.extend((0..64 * 3).map(|i| i as u8));
+
+    let img = Image {
+        width: 64,
+        height: 3,
+        img_type: ImageType::Rgb8,
+        data,
+    };
+
+    for strategy in [FilterStrategy::MinSum, FilterStrategy::BruteForce] {
+        let png = encode_png(
+            &img,
+            &PngEncodingOptions {
+                filter: Some(strategy),
+                ..Default::default()
+            },
+        );
+
+        let idat = inflated_idat(&png);
+        let filters: Vec<u8> = idat.chunks(64 * 3 + 1).map(|row| row[0]).collect();
+        assert_eq!(filters[1..], [1, 2]);
+
+        assert_eq!(decode_png(&mut png.into_iter()).unwrap(), img);
+    }
+}
+
+///Converts an image into rgba16, so images of different types can be compared
+fn to_rgba16(image: &Image) -> Vec<u8> {
+    let mut image = image.clone();
+    image.add_channels();
+    image.add_alpha();
+
+    if image.img_type.is_16_bit() {
+        image.data
+    } else {
+        image.data.iter().flat_map(|v| [*v, *v]).collect()
+    }
+}
+
+#[test]
+fn optimization() {
+    for dir in ["test-data/basic_tests", "test-data/interlaced"] {
+        for i in PathBuf::from(dir).read_dir().unwrap() {
+            let file = i.unwrap().path();
+
+            println!("\nLoading {}", file.file_name().unwrap().to_str().unwrap());
+            let data = std::fs::read(file).unwrap();
+
+            let (png, report) = optimize(&data, &OptimizationOptions::default()).unwrap();
+
+            assert!(png.len() <= data.len());
+            assert_eq!(report.original_size, data.len());
+            assert_eq!(report.optimized_size, png.len());
+            assert_eq!(report.original_format, (data[25], data[24]));
+            assert_eq!(report.optimized_format, (png[25], png[24]));
+
+            let original = decode_png(&mut data.into_iter()).unwrap();
+            let optimized = decode_png(&mut png.into_iter()).unwrap();
+
+            assert_eq!(to_rgba16(&original), to_rgba16(&optimized));
+        }
+    }
+
+    //An opaque gray 16 bit image with few colors
+    let image = Image {
+        width: 64,
+        height: 64,
+        img_type: ImageType::Rgba16,
+        data: (0..64 * 64)
+            .flat_map(|i| {
+                let v = (i % 3 * 100) as u8;
+                [v, v, v, v, v, v, 255, 255]
+            })
+            .collect(),
+    };
+
+    let png = encode_png(
+        &image,
+        &PngEncodingOptions {
+            compression: CompressionLevel::None,
+            interlaced: true,
+            text: vec![TextChunk::new("Title", "Gray")],
+            ..Default::default()
+        },
+    );
+
+    //Insert an unknown chunk right after IHDR
+    let mut with_chunk = png[..33].to_vec();
+    with_chunk.extend_from_slice(&9u32.to_be_bytes());
+    with_chunk.extend_from_slice(b"vpAg");
+    with_chunk.extend_from_slice(&[0; 13]);
+    with_chunk.extend_from_slice(&png[33..]);
+    fix_crcs(&mut with_chunk);
+
+    let (optimized, report) = optimize(&with_chunk, &OptimizationOptions::default()).unwrap();
+    assert_eq!(report.original_format, (6, 16));
+    assert!(matches!(report.optimized_format, (0 | 3, 1..=8)));
+    assert!(report.removed_interlacing);
+    assert_eq!(report.stripped_chunks, [*b"vpAg"]);
+    assert!(report.optimized_size < report.original_size / 10);
+
+    let (decoded, metadata) = decode_png_with_metadata(&mut optimized.into_iter()).unwrap();
+    assert_eq!(to_rgba16(&decoded), to_rgba16(&image));
+    assert_eq!(metadata.get_text("Title"), Some("Gray"));
+
+    let options = OptimizationOptions {
+        strip_metadata: true,
+    };
+    let (optimized, report) = optimize(&png, &options).unwrap();
+    assert_eq!(report.stripped_chunks, [*b"tEXt"]);
+    assert!(chunk_types(&optimized).iter().all(|t| t != b"tEXt"));
+
+    //Chunks the encoder can't write are reported
+    let palette = Palette {
+        colors: vec![[255, 0, 0], [0, 0, 255]],
+        alpha: Vec::new(),
+    };
+    let indexed = IndexedImage {
+        width: 64,
+        height: 64,
+        bit_depth: 8,
+        palette,
+        data: (0..64 * 64).map(|i| (i % 7 == 0) as u8).collect(),
+    };
+    let png = encode_indexed_png(
+        &indexed,
+        &PngEncodingOptions {
+            compression: CompressionLevel::None,
+            ..Default::default()
+        },
+    );
+
+    let plte_end = png.windows(4).position(|w| w == b"IDAT").unwrap() - 4;
+    let mut with_chunks = png[..plte_end].to_vec();
+    for (chunk_type, data) in [
+        (b"hIST", vec![0; 4]),
+        (b"sPLT", b"name\0\x08".to_vec()),
+        (b"bKGD", vec![1]),
+    ] {
+        with_chunks.extend_from_slice(&(data.len() as u32).to_be_bytes());
+        with_chunks.extend_from_slice(chunk_type);
+        with_chunks.extend_from_slice(&data);
+        with_chunks.extend_from_slice(&[0; 4]);
+    }
+    with_chunks.extend_from_slice(&png[plte_end..]);
+    fix_crcs(&mut with_chunks);
+
+    let (optimized, report) = optimize(&with_chunks, &OptimizationOptions::default()).unwrap();
+    assert_eq!(report.optimized_format, (3, 1));
+    assert_eq!(report.stripped_chunks, [*b"hIST", *b"sPLT", *b"bKGD"]);
+    assert!(!chunk_types(&optimized).contains(b"bKGD"));
+
+    //The background color and significant bits are kept if the format doesn't change
+    let mut rng = Rng(3);
+    let image = Image {
+        width: 64,
+        height: 64,
+        img_type: ImageType::Rgb8,
+        data: (0..64 * 64 * 3).map(|_| rng.next() as u8 & 0xf0).collect(),
+    };
+    let png = encode_png(
+        &image,
+        &PngEncodingOptions {
+            compression: CompressionLevel::None,
+            background: Some(Background::Rgb([16, 32, 48])),
+            significant_bits: Some(vec![4, 4, 4]),
+            ..Default::default()
+        },
+    );
+
+    let (optimized, report) = optimize(&png, &OptimizationOptions::default()).unwrap();
+    assert_eq!(report.optimized_format, (2, 8));
+    assert!(report.stripped_chunks.is_empty());
+
+    let (_, metadata) = decode_png_with_metadata(&mut optimized.into_iter()).unwrap();
+    assert_eq!(metadata.background, Some(Background::Rgb([16, 32, 48])));
+    assert_eq!(metadata.significant_bits, Some(vec![4, 4, 4]));
+
+    //Animations are returned unchanged
+    let apng = test_apng(true);
+    let (optimized, report) = optimize(&apng, &OptimizationOptions::default()).unwrap();
+    assert_eq!(optimized, apng);
+    assert_eq!(report.filter, None);
+
+    assert!(optimize(&[0; 8], &OptimizationOptions::default()).is_err());
+}
+
+#[test]
+fn low_bit_depth_encoding() {
+    for bit_depth in [1, 2, 4] {
+        let file = format!("test-data/basic_tests/{bit_depth}bit_grayscale.png");
+        let data = std::fs::read(file).unwrap();
+
+        let img = decode_png(&mut data.into_iter()).unwrap();
+        assert_eq!(img.img_type, ImageType::R8);
+
+        let mut reduced = img.clone();
+        assert!(reduced.reduce_bit_depth());
+        assert_eq!(reduced.img_type.bit_depth(), bit_depth);
+        assert!(reduced.data.iter().all(|v| *v < 1 << bit_depth));
+
+        for interlaced in [false, true] {
+            let png = encode_png(
+                &reduced,
+                &PngEncodingOptions {
+                    interlaced,
+                    ..Default::default()
+                },
+            );
+
+            assert_eq!(png[24], bit_depth);
+            assert_eq!(decode_png(&mut png.into_iter()).unwrap(), img);
+        }
+
+        let mut expanded = reduced.clone();
+        expanded.add_channels();
+        assert_eq!(expanded.img_type, ImageType::Rgb8);
+        assert!(
+            expanded
+                .data
+                .chunks(3)
+                .zip(&img.data)
+                .all(|(p, v)| p == [*v; 3])
+        );
+    }
+
+    //Rows are padded to whole bytes
+    let img = Image {
+        width: 3,
+        height: 2,
+        img_type: ImageType::R1,
+        data: vec![1, 0, 1, 0, 1, 0],
+    };
+    let png = encode_png(&img, &PngEncodingOptions::default());
+    assert_eq!(
+        decode_png(&mut png.into_iter()).unwrap().data,
+        [255, 0, 255, 0, 255, 0]
+    );
+
+    //Values that don't fit stay 8 bit
+    let mut img = Image {
+        width: 2,
+        height: 1,
+        img_type: ImageType::R8,
+        data: vec![0, 16],
+    };
+    assert!(!img.reduce_bit_depth());
+    assert_eq!(img.img_type, ImageType::R8);
+
+    let mut encoder = Encoder::new(
+        Vec::new(),
+        2,
+        1,
+        ImageType::R2,
+        &PngEncodingOptions::default(),
+    )
+    .unwrap();
+    assert!(encoder.write_row(&[3, 4]).is_err());
+}
+
+#[test]
+fn preserved_bit_depth() {
+    let options = DecodingOptions {
+        preserve_bit_depth: true,
+        ..Default::default()
+    };
+
+    for dir in ["test-data/basic_tests", "test-data/interlaced"] {
+        for i in PathBuf::from(dir).read_dir().unwrap() {
+            let file = i.unwrap().path();
+            let filename = file.file_name().unwrap().to_str().unwrap().to_string();
+
+            println!("\nLoading {filename}");
+            let data = std::fs::read(&file).unwrap();
+
+            let (img, _) =
+                decode_png_with_options(&mut data.clone().into_iter(), &options).unwrap();
+            let mut expected = decode_png(&mut data.clone().into_iter()).unwrap();
+
+            if filename.contains("grayscale") && data[24] < 8 {
+                assert!(expected.reduce_bit_depth());
+                assert_eq!(img.img_type.bit_depth(), data[24]);
+
+                //Re-encoding keeps the original format
+                let png = encode_png(&img, &PngEncodingOptions::default());
+                assert_eq!(png[24..26], data[24..26]);
+            }
+
+            assert_eq!(img, expected);
+        }
+    }
+
+    let header = [0, 0, 0, 3, 0, 0, 0, 1, 2, 0, 0, 0, 0];
+    let idat = zlib(&[0, 0b0001_1011]);
+
+    let png = build_png(header, &[(b"sBIT", vec![1]), (b"IDAT", idat.clone())]);
+    let (img, metadata) = decode_png_with_options(&mut png.into_iter(), &options).unwrap();
+    assert_eq!(img.img_type, ImageType::R2);
+    assert_eq!(img.data, [0, 1, 2]);
+    assert_eq!(metadata.significant_bits, Some(vec![1]));
+
+    //Malformed chunks are ignored
+    let shifted = DecodingOptions {
+        shift_significant_bits: true,
+        ..options
+    };
+    for sbit in [vec![0], vec![9], vec![8, 8]] {
+        let png = gray_1x1(&[(b"sBIT", sbit)]);
+        let (img, metadata) = decode_png_with_options(&mut png.into_iter(), &shifted).unwrap();
+        assert_eq!(img.data, [0]);
+        assert_eq!(metadata.significant_bits, None);
+    }
+}
+
+#[test]
+fn byte_order() {
+    let data = std::fs::read("test-data/basic_tests/16bit_truecolor_alpha.png").unwrap();
+    let little = decode_png(&mut data.clone().into_iter()).unwrap();
+
+    for byte_order in [
+        ByteOrder::LittleEndian,
+        ByteOrder::BigEndian,
+        ByteOrder::Native,
+    ] {
+        let options = DecodingOptions {
+            byte_order,
+            ..Default::default()
+        };
+        let (img, _) = decode_png_with_options(&mut data.clone().into_iter(), &options).unwrap();
+
+        let expected: Vec<u8> = little
+            .data
+            .chunks(2)
+            .flat_map(|s| byte_order.to_bytes(u16::from_le_bytes([s[0], s[1]])))
+            .collect();
+        assert_eq!(img.data, expected);
+
+        let mut converted = little.clone();
+        converted.convert_byte_order(ByteOrder::LittleEndian, byte_order);
+        assert_eq!(converted, img);
+
+        //Encoding with the same byte order produces the same image
+        let png = encode_png(
+            &img,
+            &PngEncodingOptions {
+                byte_order,
+                ..Default::default()
+            },
+        );
+        assert_eq!(decode_png(&mut png.into_iter()).unwrap(), little);
+
+        if byte_order == ByteOrder::Native {
+            let samples = img.as_u16().unwrap();
+            assert_eq!(samples.len(), img.data.len() / 2);
+
+            let first = img.get_pixel::<4>(0, 0).unwrap();
+            assert_eq!(first, samples[..4]);
+
+            let (x, y) = (img.width - 1, img.height - 1);
+            let last = img.get_pixel::<4>(x, y).unwrap();
+            assert_eq!(last, samples[samples.len() - 4..]);
+        }
+    }
+
+    let mut img = Image {
+        width: 2,
+        height: 2,
+        img_type: ImageType::Ra16,
+        data: vec![0; 16],
+    };
+
+    img.put_pixel(1, 0, [0x1234, 0xffff]);
+    assert_eq!(img.get_pixel(1, 0), Some([0x1234, 0xffff]));
+    assert_eq!(img.get_pixel::<2>(2, 0), None);
+    assert_eq!(img.get_pixel::<3>(0, 0), None);
+
+    let samples = img.as_u16_mut().unwrap();
+    samples[0] = 7;
+    assert_eq!(img.get_pixel(0, 0), Some([7, 0]));
+
+    let mut img = Image {
+        width: 2,
+        height: 1,
+        img_type: ImageType::Rgb8,
+        data: vec![0; 6],
+    };
+    assert_eq!(img.as_u16(), None);
+
+    img.put_pixel(0, 0, [1, 2, 3]);
+    assert_eq!(img.data, [1, 2, 3, 0, 0, 0]);
+    assert_eq!(img.get_pixel(1, 0), Some([0, 0, 0]));
+
+    //8 bit images don't have a byte order
+    img.convert_byte_order(ByteOrder::LittleEndian, ByteOrder::BigEndian);
+    assert_eq!(img.data, [1, 2, 3, 0, 0, 0]);
+
+    //Flattening uses the given byte order, so it works on the samples of the accessors
+    let mut img = Image {
+        width: 1,
+        height: 1,
+        img_type: ImageType::Ra16,
+        data: vec![0; 4],
+    };
+    img.put_pixel(0, 0, [0x1234, 0xffff]);
+    img.flatten(Background::Gray(0), ByteOrder::Native);
+    assert_eq!(img.get_pixel(0, 0), Some([0x1234]));
+
+    //Cropped animation frames are composited in the requested byte order
+    let frame = |red: u16, alpha: u16| {
+        let mut image = Image {
+            width: 2,
+            height: 1,
+            img_type: ImageType::Rgba16,
+            data: vec![0; 16],
+        };
+        image.put_pixel(0, 0, [red, 2000, 3000, alpha]);
+        image.put_pixel(1, 0, [4, 5, 6, 0xffff]);
+        image.convert_byte_order(ByteOrder::Native, ByteOrder::BigEndian);
+
+        Frame {
+            control: FrameControl {
+                width: 2,
+                height: 1,
+                x_offset: 0,
+                y_offset: 0,
+                delay_num: 1,
+                delay_den: 10,
+                dispose_op: DisposeOp::None,
+                blend_op: BlendOp::Over,
+            },
+            image,
+        }
+    };
+    let animation = Animation {
+        width: 2,
+        height: 1,
+        num_plays: 0,
+        frames: vec![frame(1000, 0xffff), frame(50000, 0x8000)],
+        default_image: None,
+    };
+
+    let options = PngEncodingOptions {
+        byte_order: ByteOrder::BigEndian,
+        crop_frames: true,
+        ..Default::default()
+    };
+    let png = encode_apng(&animation, &options).unwrap();
+    let decoded = decode_apng(&mut png.into_iter()).unwrap();
+
+    let mut expected = animation.composite(ByteOrder::BigEndian);
+    for canvas in &mut expected {
+        canvas.convert_byte_order(ByteOrder::BigEndian, ByteOrder::LittleEndian);
+    }
+
+    assert_eq!(decoded.frames[1].image.width, 1);
+    assert_eq!(decoded.composite(ByteOrder::LittleEndian), expected);
+}
+
+#[test]
+fn color_space_metadata() {
+    let img = Image {
+        width: 2,
+        height: 2,
+        img_type: ImageType::Rgb8,
+        data: (0..12).collect(),
+    };
+
+    let chromaticities = Chromaticities {
+        white_point: (31270, 32900),
+        red: (64000, 33000),
+        green: (30000, 60000),
+        blue: (15000, 6000),
+    };
+    let icc_profile = IccProfile {
+        name: "Display P3".to_string(),
+        data: (0..1000).map(|i| (i % 7) as u8).collect(),
+    };
+
+    let options = PngEncodingOptions {
+        gamma: Some(Gamma(45455)),
+        chromaticities: Some(chromaticities),
+        icc_profile: Some(icc_profile.clone()),
+        ..Default::default()
+    };
+
+    let png = encode_png(&img, &options);
+    assert_eq!(chunk_types(&png)[1..4], [*b"gAMA", *b"cHRM", *b"iCCP"]);
+
+    let (decoded, metadata) = decode_png_with_metadata(&mut png.clone().into_iter()).unwrap();
+    assert_eq!(decoded, img);
+    assert_eq!(metadata.gamma, Some(Gamma(45455)));
+    assert!((metadata.gamma.unwrap().value() - 1.0 / 2.2).abs() < 1e-5);
+    assert_eq!(metadata.chromaticities, Some(chromaticities));
+    assert_eq!(metadata.icc_profile, Some(icc_profile.clone()));
+    assert_eq!(metadata.srgb, None);
+
+    //The optimizer keeps color space information
+    let (optimized, _) = optimize(&png, &OptimizationOptions::default()).unwrap();
+    let (_, optimized) = decode_png_with_metadata(&mut optimized.into_iter()).unwrap();
+    assert_eq!(optimized.icc_profile, Some(icc_profile.clone()));
+    assert_eq!(optimized.gamma, Some(Gamma(45455)));
+
+    for intent in [
+        RenderingIntent::Perceptual,
+        RenderingIntent::RelativeColorimetric,
+        RenderingIntent::Saturation,
+        RenderingIntent::AbsoluteColorimetric,
+    ] {
+        let png = encode_png(
+            &img,
+            &PngEncodingOptions {
+                srgb: Some(intent),
+                ..Default::default()
+            },
+        );
+
+        let (_, metadata) = decode_png_with_metadata(&mut png.into_iter()).unwrap();
+        assert_eq!(metadata.srgb, Some(intent));
+    }
+
+    //sRGB and ICC profiles are mutually exclusive
+    let both = PngEncodingOptions {
+        srgb: Some(RenderingIntent::Perceptual),
+        icc_profile: Some(icc_profile),
+        ..Default::default()
+    };
+    assert!(Encoder::new(Vec::new(), 1, 1, ImageType::R8, &both).is_err());
+
+    //Malformed chunks are ignored
+    for (chunk_type, data) in [
+        (b"gAMA", vec![0; 3]),
+        (b"cHRM", vec![0; 31]),
+        (b"sRGB", vec![4]),
+        (b"iCCP", vec![0, 0, 0]),
+        (b"iCCP", b"name\0\x01".to_vec()),
+        (b"iCCP", b"name\0\0not zlib".to_vec()),
+    ] {
+        let png = gray_1x1(&[(chunk_type, data)]);
+        let (img, metadata) = decode_png_with_metadata(&mut png.into_iter()).unwrap();
+
+        assert_eq!(img.data, [0]);
+        assert_eq!(metadata.gamma, None);
+        assert_eq!(metadata.chromaticities, None);
+        assert_eq!(metadata.srgb, None);
+        assert_eq!(metadata.icc_profile, None);
+    }
+}
+
+#[test]
+fn color_conversion() {
+    let img = Image {
+        width: 3,
+        height: 1,
+        img_type: ImageType::Ra8,
+        data: vec![0, 255, 128, 128, 255, 0],
+    };
+
+    let decode = |options: &PngEncodingOptions, conversion| {
+        let png = encode_png(&img, options);
+        let options = DecodingOptions {
+            color_conversion: Some(conversion),
+            ..Default::default()
+        };
+
+        decode_png_with_options(&mut png.into_iter(), &options)
+            .unwrap()
+            .0
+    };
+
+    //Linear gamma only rescales the samples
+    let linear = PngEncodingOptions {
+        gamma: Some(Gamma(100_000)),
+        ..Default::default()
+    };
+    let decoded = decode(&linear, ColorConversion::Linear);
+    assert_eq!(decoded.img_type, ImageType::Ra32F);
+
+    let samples = decoded.as_f32().unwrap();
+    let expected = [0.0, 1.0, 128.0 / 255.0, 128.0 / 255.0, 1.0, 0.0];
+    assert!(
+        samples
+            .iter()
+            .zip(expected)
+            .all(|(a, b)| (a - b).abs() < 1e-6)
+    );
+
+    //Brighter in sRGB, alpha is left alone
+    let decoded = decode(&linear, ColorConversion::Srgb);
+    assert_eq!(decoded.img_type, ImageType::Ra8);
+    assert_eq!(decoded.data, [0, 255, 188, 128, 255, 0]);
+
+    //Images without color space information are assumed to be sRGB
+    let decoded = decode(&PngEncodingOptions::default(), ColorConversion::Srgb);
+    assert_eq!(decoded, img);
+
+    let srgb = PngEncodingOptions {
+        srgb: Some(RenderingIntent::Perceptual),
+        //Ignored in favor of the sRGB chunk
+        gamma: Some(Gamma(100_000)),
+        ..Default::default()
+    };
+    let decoded = decode(&srgb, ColorConversion::Linear);
+    let samples = decoded.as_f32().unwrap();
+    assert!((samples[2] - 0.2158).abs() < 1e-4);
+
+    //16 bit images keep their bit depth and byte order
+    let img16 = Image {
+        width: 2,
+        height: 1,
+        img_type: ImageType::R16,
+        data: vec![0, 0x80, 0xff, 0xff],
+    };
+    let png = encode_png(&img16, &linear);
+    let options = DecodingOptions {
+        color_conversion: Some(ColorConversion::Srgb),
+        byte_order: ByteOrder::BigEndian,
+        ..Default::default()
+    };
+    let (decoded, _) = decode_png_with_options(&mut png.into_iter(), &options).unwrap();
+    assert_eq!(decoded.img_type, ImageType::R16);
+    assert!(decoded.data[0] > 0xb0);
+    assert_eq!(decoded.data[2..], [0xff, 0xff]);
+
+    //Float images can't be encoded
+    assert!(Encoder::new(Vec::new(), 1, 1, ImageType::Rgb32F, &Default::default()).is_err());
+    let mut float = decode(&linear, ColorConversion::Linear);
+    float.add_channels();
+    assert_eq!(float.img_type, ImageType::Rgba32F);
+    assert_eq!(float.as_f32().unwrap()[4..8], [128.0 / 255.0; 4]);
+    assert_eq!(float.get_pixel::<4>(0, 0), None);
+}
+
+#[test]
+fn hdr_metadata() {
+    let img = Image {
+        width: 2,
+        height: 1,
+        img_type: ImageType::Rgb16,
+        data: (0..12).collect(),
+    };
+
+    let mastering_display = MasteringDisplay {
+        primaries: [(35400, 14600), (8500, 39850), (6550, 2300)],
+        white_point: (15635, 16450),
+        max_luminance: 10_000_000,
+        min_luminance: 50,
+    };
+
+    let options = PngEncodingOptions {
+        cicp: Some(CodingIndependentCodePoints::BT2100_PQ),
+        mastering_display: Some(mastering_display),
+        ..Default::default()
+    };
+
+    let png = encode_png(&img, &options);
+    assert_eq!(chunk_types(&png)[1..3], [*b"cICP", *b"mDCv"]);
+
+    let (decoded, metadata) = decode_png_with_metadata(&mut png.clone().into_iter()).unwrap();
+    assert_eq!(decoded, img);
+
+    let cicp = metadata.cicp.unwrap();
+    assert_eq!(cicp, CodingIndependentCodePoints::BT2100_PQ);
+    assert!(cicp.is_pq() && !cicp.is_hlg());
+    assert_eq!(metadata.mastering_display, Some(mastering_display));
+    assert!((mastering_display.max_luminance_nits() - 1000.0).abs() < 1e-9);
+    assert!((mastering_display.min_luminance_nits() - 0.005).abs() < 1e-9);
+
+    //The optimizer keeps HDR information
+    let (optimized, _) = optimize(&png, &OptimizationOptions::default()).unwrap();
+    let (_, optimized) = decode_png_with_metadata(&mut optimized.into_iter()).unwrap();
+    assert_eq!(optimized.cicp, Some(CodingIndependentCodePoints::BT2100_PQ));
+    assert_eq!(optimized.mastering_display, Some(mastering_display));
+
+    let ycbcr = PngEncodingOptions {
+        cicp: Some(CodingIndependentCodePoints {
+            matrix_coefficients: 1,
+            ..CodingIndependentCodePoints::BT2100_HLG
+        }),
+        ..Default::default()
+    };
+    assert!(Encoder::new(Vec::new(), 1, 1, ImageType::Rgb8, &ycbcr).is_err());
+
+    //Malformed chunks are ignored
+    for (chunk_type, data) in [
+        (b"cICP", vec![9, 16, 0]),
+        (b"cICP", vec![9, 16, 1, 1]),
+        (b"cICP", vec![9, 16, 0, 2]),
+        (b"mDCv", vec![0; 23]),
+    ] {
+        let png = gray_1x1(&[(chunk_type, data)]);
+        let (img, metadata) = decode_png_with_metadata(&mut png.into_iter()).unwrap();
+
+        assert_eq!(img.data, [0]);
+        assert_eq!(metadata.cicp, None);
+        assert_eq!(metadata.mastering_display, None);
+    }
+}
+
+#[test]
+fn physical_dimensions() {
+    let img = Image {
+        width: 1,
+        height: 1,
+        img_type: ImageType::R8,
+        data: vec![0],
+    };
+
+    let dimensions = PhysicalDimensions::from_dpi(300.0);
+    assert_eq!(dimensions.x, 11811);
+    assert_eq!(dimensions.y, 11811);
+
+    let (x, y) = dimensions.dpi().unwrap();
+    assert!((x - 300.0).abs() < 0.01 && (y - 300.0).abs() < 0.01);
+
+    let options = PngEncodingOptions {
+        physical_dimensions: Some(dimensions),
+        ..Default::default()
+    };
+    let png = encode_png(&img, &options);
+    assert_eq!(chunk_types(&png)[1], *b"pHYs");
+
+    let (_, metadata) = decode_png_with_metadata(&mut png.into_iter()).unwrap();
+    assert_eq!(metadata.physical_dimensions, Some(dimensions));
+
+    //Only the aspect ratio is known
+    let aspect = PhysicalDimensions {
+        x: 2,
+        y: 1,
+        unit: PixelUnit::Unknown,
+    };
+    assert_eq!(aspect.dpi(), None);
+
+    let options = PngEncodingOptions {
+        physical_dimensions: Some(aspect),
+        ..Default::default()
+    };
+    let png = encode_png(&img, &options);
+    let (_, metadata) = decode_png_with_metadata(&mut png.into_iter()).unwrap();
+    assert_eq!(metadata.physical_dimensions, Some(aspect));
+
+    //Malformed chunks are ignored
+    for data in [vec![0; 8], vec![0, 0, 0, 1, 0, 0, 0, 1, 2]] {
+        let png = gray_1x1(&[(b"pHYs", data)]);
+        let (img, metadata) = decode_png_with_metadata(&mut png.into_iter()).unwrap();
+
+        assert_eq!(img.data, [0]);
+        assert_eq!(metadata.physical_dimensions, None);
+    }
+}
+
+#[test]
+fn background_color() {
+    let idat = |data: &[u8]| (b"IDAT", zlib(data));
+
+    //Gray, scaled like the samples
+    let png = build_png(
+        [0, 0, 0, 1, 0, 0, 0, 1, 2, 0, 0, 0, 0],
+        &[(b"bKGD", vec![0, 2]), idat(&[0, 0])],
+    );
+    let (_, metadata) = decode_png_with_metadata(&mut png.clone().into_iter()).unwrap();
+    assert_eq!(metadata.background, Some(Background::Gray(170)));
+
+    let options = DecodingOptions {
+        preserve_bit_depth: true,
+        ..Default::default()
+    };
+    let (_, metadata) = decode_png_with_options(&mut png.into_iter(), &options).unwrap();
+    assert_eq!(metadata.background, Some(Background::Gray(2)));
+
+    //Rgb
+    let png = build_png(
+        [0, 0, 0, 1, 0, 0, 0, 1, 16, 2, 0, 0, 0],
+        &[(b"bKGD", vec![0, 1, 1, 0, 255, 255]), idat(&[0; 7])],
+    );
+    let (_, metadata) = decode_png_with_metadata(&mut png.into_iter()).unwrap();
+    assert_eq!(metadata.background, Some(Background::Rgb([1, 256, 65535])));
+
+    //Palette indices are resolved
+    let png = build_png(
+        [0, 0, 0, 1, 0, 0, 0, 1, 8, 3, 0, 0, 0],
+        &[
+            (b"PLTE", vec![1, 2, 3, 4, 5, 6]),
+            (b"bKGD", vec![1]),
+            idat(&[0, 0]),
+        ],
+    );
+    let (_, metadata) = decode_png_with_metadata(&mut png.into_iter()).unwrap();
+    assert_eq!(metadata.background, Some(Background::Rgb([4, 5, 6])));
+
+    //Malformed chunks are ignored
+    for png in [
+        gray_1x1(&[(b"bKGD", vec![1, 0])]),
+        gray_1x1(&[(b"bKGD", vec![0; 6])]),
+        build_png(
+            [0, 0, 0, 1, 0, 0, 0, 1, 8, 2, 0, 0, 0],
+            &[(b"bKGD", vec![0, 0]), idat(&[0; 4])],
+        ),
+        build_png(
+            [0, 0, 0, 1, 0, 0, 0, 1, 8, 3, 0, 0, 0],
+            &[(b"PLTE", vec![0; 3]), (b"bKGD", vec![1]), idat(&[0, 0])],
+        ),
+    ] {
+        let (_, metadata) = decode_png_with_metadata(&mut png.into_iter()).unwrap();
+        assert_eq!(metadata.background, None);
+    }
+
+    //Encoding, gray backgrounds are expanded for rgb images
+    let encode = |img_type, data, background| {
+        let image = Image {
+            width: 1,
+            height: 1,
+            img_type,
+            data,
+        };
+        let options = PngEncodingOptions {
+            background: Some(background),
+            ..Default::default()
+        };
+
+        try_encode_png(&image, &options)
+            .map(|png| decode_png_with_metadata(&mut png.into_iter()).unwrap().1)
+    };
+
+    for (img_type, data, background, expected) in [
+        (
+            ImageType::R4,
+            vec![0],
+            Background::Gray(5),
+            Background::Gray(5 * 17),
+        ),
+        (
+            ImageType::R16,
+            vec![0; 2],
+            Background::Gray(1000),
+            Background::Gray(1000),
+        ),
+        (
+            ImageType::Rgb8,
+            vec![0; 3],
+            Background::Gray(7),
+            Background::Rgb([7; 3]),
+        ),
+        (
+            ImageType::Rgba16,
+            vec![0; 8],
+            Background::Rgb([1, 256, 65535]),
+            Background::Rgb([1, 256, 65535]),
+        ),
+    ] {
+        let metadata = encode(img_type, data, background).unwrap();
+        assert_eq!(metadata.background, Some(expected));
+    }
+
+    for (img_type, data, background) in [
+        (ImageType::R4, vec![0], Background::Gray(16)),
+        (ImageType::R8, vec![0], Background::Rgb([1, 2, 3])),
+        (ImageType::Rgb8, vec![0; 3], Background::Rgb([0, 256, 0])),
+    ] {
+        assert!(encode(img_type, data, background).is_err());
+    }
+
+    let palette = Palette {
+        colors: vec![[1, 2, 3], [4, 5, 6]],
+        alpha: Vec::new(),
+    };
+    let indexed = IndexedImage {
+        width: 1,
+        height: 1,
+        bit_depth: 1,
+        palette,
+        data: vec![0],
+    };
+
+    let options = PngEncodingOptions {
+        background: Some(Background::Rgb([4, 5, 6])),
+        ..Default::default()
+    };
+    let png = encode_indexed_png(&indexed, &options);
+    let (_, metadata) = decode_png_with_metadata(&mut png.into_iter()).unwrap();
+    assert_eq!(metadata.background, Some(Background::Rgb([4, 5, 6])));
+
+    let options = PngEncodingOptions {
+        background: Some(Background::Rgb([7, 8, 9])),
+        ..Default::default()
+    };
+    assert!(try_encode_indexed_png(&indexed, &options).is_err());
+
+    //Flattening
+    let mut image = Image {
+        width: 3,
+        height: 1,
+        img_type: ImageType::Ra8,
+        data: vec![200, 255, 200, 0, 200, 128],
+    };
+
+    let mut gray = image.clone();
+    gray.flatten(Background::Gray(100), ByteOrder::LittleEndian);
+    assert_eq!(gray.img_type, ImageType::R8);
+    assert_eq!(gray.data, [200, 100, 150]);
+
+    image.flatten(Background::Rgb([0, 100, 255]), ByteOrder::LittleEndian);
+    assert_eq!(image.img_type, ImageType::Rgb8);
+    assert_eq!(image.data, [200, 200, 200, 0, 100, 255, 100, 150, 227]);
+
+    let mut image = Image {
+        width: 1,
+        height: 1,
+        img_type: ImageType::Rgba16,
+        data: [1000u16, 2000, 3000, 32768]
+            .iter()
+            .flat_map(|v| v.to_le_bytes())
+            .collect(),
+    };
+    image.flatten(Background::Gray(0), ByteOrder::LittleEndian);
+    assert_eq!(image.img_type, ImageType::Rgb16);
+    assert_eq!(
+        image.data,
+        [500u16, 1000, 1500].map(u16::to_le_bytes).concat()
+    );
+
+    //Opaque images are left alone
+    let mut opaque = Image {
+        width: 1,
+        height: 1,
+        img_type: ImageType::Rgb8,
+        data: vec![1, 2, 3],
+    };
+    opaque.flatten(Background::Gray(0), ByteOrder::LittleEndian);
+    assert_eq!(opaque.data, [1, 2, 3]);
+}
+
+#[test]
+fn significant_bits() {
+    //10 bit samples in a 16 bit image
+    let samples = [0u16, 1, 512, 1023];
+    let img = Image {
+        width: 2,
+        height: 1,
+        img_type: ImageType::Ra16,
+        data: samples.iter().flat_map(|v| v.to_le_bytes()).collect(),
+    };
+
+    let options = PngEncodingOptions {
+        significant_bits: Some(vec![10, 10]),
+        replicate_significant_bits: true,
+        ..Default::default()
+    };
+    let png = encode_png(&img, &options);
+    assert_eq!(chunk_types(&png)[1], *b"sBIT");
+
+    //Bits are replicated, so the maximum maps to the maximum
+    let (decoded, metadata) = decode_png_with_metadata(&mut png.clone().into_iter()).unwrap();
+    assert_eq!(metadata.significant_bits, Some(vec![10, 10]));
+    assert_eq!(
+        decoded.data,
+        [0u16, 0x40, 0x8020, 0xffff].map(u16::to_le_bytes).concat()
+    );
+
+    let options = DecodingOptions {
+        shift_significant_bits: true,
+        ..Default::default()
+    };
+    let (decoded, _) = decode_png_with_options(&mut png.into_iter(), &options).unwrap();
+    assert_eq!(decoded, img);
+
+    //The background is shifted like the samples
+    let png = encode_png(
+        &img,
+        &PngEncodingOptions {
+            significant_bits: Some(vec![10, 10]),
+            background: Some(Background::Gray(0x8020)),
+            ..Default::default()
+        },
+    );
+    let (_, metadata) = decode_png_with_options(&mut png.into_iter(), &options).unwrap();
+    assert_eq!(metadata.background, Some(Background::Gray(0x200)));
+
+    //Without replication the samples are stored as is
+    let png = encode_png(
+        &img,
+        &PngEncodingOptions {
+            significant_bits: Some(vec![10, 10]),
+            ..Default::default()
+        },
+    );
+    let (decoded, _) = decode_png_with_metadata(&mut png.into_iter()).unwrap();
+    assert_eq!(decoded, img);
+
+    //8 bit rgb with 5 significant bits
+    let img = Image {
+        width: 1,
+        height: 1,
+        img_type: ImageType::Rgb8,
+        data: vec![31, 16, 1],
+    };
+    let options = PngEncodingOptions {
+        significant_bits: Some(vec![5, 5, 5]),
+        replicate_significant_bits: true,
+        ..Default::default()
+    };
+    let png = encode_png(&img, &options);
+    let (decoded, _) = decode_png_with_metadata(&mut png.clone().into_iter()).unwrap();
+    assert_eq!(decoded.data, [255, 132, 8]);
+
+    let options = DecodingOptions {
+        shift_significant_bits: true,
+        ..Default::default()
+    };
+    let (decoded, _) = decode_png_with_options(&mut png.into_iter(), &options).unwrap();
+    assert_eq!(decoded, img);
+
+    //Invalid settings
+    for (img_type, bits) in [
+        (ImageType::Rgb8, vec![8, 8]),
+        (ImageType::R8, vec![9]),
+        (ImageType::Ra16, vec![0, 16]),
+    ] {
+        let options = PngEncodingOptions {
+            significant_bits: Some(bits),
+            ..Default::default()
+        };
+        assert!(Encoder::new(Vec::new(), 1, 1, img_type, &options).is_err());
+    }
+
+    let options = PngEncodingOptions {
+        significant_bits: Some(vec![4]),
+        replicate_significant_bits: true,
+        ..Default::default()
+    };
+    let mut encoder = Encoder::new(Vec::new(), 1, 1, ImageType::R8, &options).unwrap();
+    assert!(encoder.write_row(&[16]).is_err());
+    assert!(encoder.write_row(&[15]).is_ok());
+
+    //Every bit of a 16 bit sample is significant
+    let img = Image {
+        width: 2,
+        height: 1,
+        img_type: ImageType::R16,
+        data: [0u16, 0xffff].map(u16::to_le_bytes).concat(),
+    };
+    let options = PngEncodingOptions {
+        significant_bits: Some(vec![16]),
+        replicate_significant_bits: true,
+        ..Default::default()
+    };
+    let png = try_encode_png(&img, &options).unwrap();
+    let (decoded, _) = decode_png_with_metadata(&mut png.into_iter()).unwrap();
+    assert_eq!(decoded, img);
+}
+
+#[test]
+fn exif_orientation() {
+    //Little endian TIFF header with a single IFD holding the orientation
+    let exif = |orientation: u16| {
+        let mut exif = b"II*\0".to_vec();
+        exif.extend_from_slice(&8u32.to_le_bytes());
+        exif.extend_from_slice(&1u16.to_le_bytes());
+        exif.extend_from_slice(&0x0112u16.to_le_bytes());
+        exif.extend_from_slice(&3u16.to_le_bytes());
+        exif.extend_from_slice(&1u32.to_le_bytes());
+        exif.extend_from_slice(&orientation.to_le_bytes());
+        exif.extend_from_slice(&[0; 2]);
+        exif.extend_from_slice(&0u32.to_le_bytes());
+        exif
+    };
+
+    let img = Image {
+        width: 3,
+        height: 2,
+        img_type: ImageType::R8,
+        data: (0..6).collect(),
+    };
+
+    let expected: [(Orientation, u32, [u8; 6]); 8] = [
+        (Orientation::Normal, 3, [0, 1, 2, 3, 4, 5]),
+        (Orientation::FlipHorizontal, 3, [2, 1, 0, 5, 4, 3]),
+        (Orientation::Rotate180, 3, [5, 4, 3, 2, 1, 0]),
+        (Orientation::FlipVertical, 3, [3, 4, 5, 0, 1, 2]),
+        (Orientation::Transpose, 2, [0, 3, 1, 4, 2, 5]),
+        (Orientation::Rotate90, 2, [3, 0, 4, 1, 5, 2]),
+        (Orientation::Transverse, 2, [5, 2, 4, 1, 3, 0]),
+        (Orientation::Rotate270, 2, [2, 5, 1, 4, 0, 3]),
+    ];
+
+    for (value, (orientation, width, data)) in (1..).zip(expected) {
+        let options = PngEncodingOptions {
+            exif: Some(exif(value)),
+            ..Default::default()
+        };
+        let png = encode_png(&img, &options);
+
+        let (decoded, metadata) = decode_png_with_metadata(&mut png.clone().into_iter()).unwrap();
+        assert_eq!(decoded, img);
+        assert_eq!(metadata.exif, Some(exif(value)));
+        assert_eq!(metadata.orientation(), Some(orientation));
+
+        let options = DecodingOptions {
+            auto_orient: true,
+            ..Default::default()
+        };
+        let (decoded, metadata) = decode_png_with_options(&mut png.into_iter(), &options).unwrap();
+        assert_eq!(decoded.width, width);
+        assert_eq!(decoded.height, 6 / width);
+        assert_eq!(decoded.data, data);
+        assert_eq!(metadata.orientation(), Some(Orientation::Normal));
+    }
+
+    //Big endian data
+    let mut big_endian = b"MM\0*".to_vec();
+    big_endian.extend_from_slice(&8u32.to_be_bytes());
+    big_endian.extend_from_slice(&1u16.to_be_bytes());
+    big_endian.extend_from_slice(&[0x01, 0x12, 0, 3, 0, 0, 0, 1, 0, 8, 0, 0]);
+    let metadata = Metadata {
+        exif: Some(big_endian),
+        ..Default::default()
+    };
+    assert_eq!(metadata.orientation(), Some(Orientation::Rotate270));
+
+    //Missing or invalid orientation
+    let metadata = Metadata {
+        exif: Some(exif(9)),
+        ..Default::default()
+    };
+    assert_eq!(metadata.orientation(), None);
+    let metadata = Metadata {
+        exif: Some(b"II*\0\xff\0\0\0".to_vec()),
+        ..Default::default()
+    };
+    assert_eq!(metadata.orientation(), None);
+
+    let options = PngEncodingOptions {
+        exif: Some(b"not exif".to_vec()),
+        ..Default::default()
+    };
+    assert!(Encoder::new(Vec::new(), 1, 1, ImageType::R8, &options).is_err());
+
+    //Only the first valid chunk is kept
+    for (chunks, expected) in [
+        (vec![(b"eXIf", b"JFIF".to_vec())], None),
+        (
+            vec![(b"eXIf", b"JFIF".to_vec()), (b"eXIf", exif(3))],
+            Some(exif(3)),
+        ),
+        (vec![(b"eXIf", exif(1)), (b"eXIf", exif(6))], Some(exif(1))),
+    ] {
+        let png = gray_1x1(&chunks);
+        let (img, metadata) = decode_png_with_metadata(&mut png.into_iter()).unwrap();
+        assert_eq!(img.data, [0]);
+        assert_eq!(metadata.exif, expected);
+    }
+}
+
+#[test]
+fn modification_time() {
+    let time = |year, month, day, hour, minute, second| ModificationTime {
+        year,
+        month,
+        day,
+        hour,
+        minute,
+        second,
+    };
+
+    for (seconds, expected) in [
+        (0, time(1970, 1, 1, 0, 0, 0)),
+        (951_782_400, time(2000, 2, 29, 0, 0, 0)),
+        (1_709_251_199, time(2024, 2, 29, 23, 59, 59)),
+        (1_719_792_000, time(2024, 7, 1, 0, 0, 0)),
+        (4_102_444_800, time(2100, 1, 1, 0, 0, 0)),
+    ] {
+        assert_eq!(ModificationTime::from_unix_time(seconds), expected);
+    }
+
+    let img = Image {
+        width: 1,
+        height: 1,
+        img_type: ImageType::R8,
+        data: vec![0],
+    };
+
+    //Day and month are stored separately
+    let written = time(2024, 7, 15, 13, 45, 30);
+    let options = PngEncodingOptions {
+        modification_time: Some(written),
+        write_timestamp: true,
+        ..Default::default()
+    };
+    let png = encode_png(&img, &options);
+    assert_eq!(chunk_types(&png)[1], *b"tIME");
+    assert_eq!(png[41..48], [0x07, 0xe8, 7, 15, 13, 45, 30]);
+
+    //Reproducible output
+    assert_eq!(png, encode_png(&img, &options));
+
+    let (_, metadata) = decode_png_with_metadata(&mut png.into_iter()).unwrap();
+    assert_eq!(metadata.modification_time, Some(written));
+
+    let options = PngEncodingOptions {
+        write_timestamp: true,
+        ..Default::default()
+    };
+    let before = ModificationTime::now();
+    let png = encode_png(&img, &options);
+    let (_, metadata) = decode_png_with_metadata(&mut png.into_iter()).unwrap();
+    let now = metadata.modification_time.unwrap();
+    assert!(now >= before && now <= ModificationTime::now());
+
+    let options = PngEncodingOptions {
+        modification_time: Some(time(2024, 13, 1, 0, 0, 0)),
+        ..Default::default()
+    };
+    assert!(Encoder::new(Vec::new(), 1, 1, ImageType::R8, &options).is_err());
+
+    //Malformed chunks are ignored
+    for data in [vec![7, 232, 7, 15, 13, 45], vec![7, 232, 7, 0, 13, 45, 30]] {
+        let png = gray_1x1(&[(b"tIME", data)]);
+        let (img, metadata) = decode_png_with_metadata(&mut png.into_iter()).unwrap();
+        assert_eq!(img.data, [0]);
+        assert_eq!(metadata.modification_time, None);
+    }
+
+    #[cfg(feature = "chrono")]
+    {
+        use chrono::TimeZone;
+
+        let offset = chrono::FixedOffset::east_opt(2 * 3600).unwrap();
+        let local = offset.with_ymd_and_hms(2024, 7, 15, 15, 45, 30).unwrap();
+        assert_eq!(ModificationTime::from(local), written);
+    }
+}
+
+///64 bit FNV-1a hash, used to pin encoder output
+fn fnv1a(data: &[u8]) -> u64 {
+    data.iter().fold(0xcbf2_9ce4_8422_2325, |hash, b| {
+        (hash ^ *b as u64).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+#[test]
+fn deterministic_encoding() {
+    let mut rng = Rng(7);
+
+    let gradient = Image {
+        width: 32,
+        height: 32,
+        img_type: ImageType::Rgb8,
+        data: (0..32 * 32 * 3).map(|i| (i % 251) as u8).collect(),
+    };
+    let noise = Image {
+        width: 16,
+        height: 8,
+        img_type: ImageType::Rgba16,
+        data: (0..16 * 8 * 8).map(|_| rng.next() as u8).collect(),
+    };
+
+    let options = |compression, filter| PngEncodingOptions {
+        compression,
+        filter,
+        deterministic: true,
+        text: vec![TextChunk::new("Software", "lunar-png")],
+        ..Default::default()
+    };
+
+    let cases = [
+        (&gradient, options(CompressionLevel::None, None)),
+        (&gradient, options(CompressionLevel::Fast, None)),
+        (&gradient, options(CompressionLevel::Best, None)),
+        (
+            &gradient,
+            options(CompressionLevel::Best, Some(FilterStrategy::BruteForce)),
+        ),
+        (&noise, options(CompressionLevel::Fast, None)),
+        (
+            &noise,
+            PngEncodingOptions {
+                interlaced: true,
+                write_timestamp: true,
+                modification_time: Some(ModificationTime::from_unix_time(1_700_000_000)),
+                ..options(CompressionLevel::Best, Some(FilterStrategy::MinSum))
+            },
+        ),
+        (
+            &gradient,
+            PngEncodingOptions {
+                quantize: Some(Quantization {
+                    max_colors: 16,
+                    dithering: true,
+                }),
+                ..options(CompressionLevel::Fast, None)
+            },
+        ),
+    ];
+
+    let hashes: Vec<u64> = cases
+        .iter()
+        .map(|(image, options)| {
+            let png = encode_png(image, options);
+
+            //Only an explicit time is written
+            assert_eq!(
+                chunk_types(&png).contains(b"tIME"),
+                options.modification_time.is_some()
+            );
+            assert_eq!(png, encode_png(image, options));
+
+            fnv1a(&png)
+        })
+        .collect();
+
+    //Pinned, so any change to the output is noticed. The hashes depend on the flate2 backend and
+    //its version, and have to be regenerated when either changes the compressed output
+    assert_eq!(
+        hashes,
+        [
+            0x351e179ad1656ca3,
+            0xfbe1ec9107e5beb3,
+            0xdd66cb0ced3c8323,
+            0x04cfa03daaa39ecb,
+            0x6c58d52530991e1c,
+            0x5eb641b3d35732de,
+            0x02d5e1a44863f80f,
+        ]
+    );
+
+    //The current time would make the output depend on when it was encoded
+    let now = PngEncodingOptions {
+        write_timestamp: true,
+        ..options(CompressionLevel::Fast, None)
+    };
+    assert!(try_encode_png(&gradient, &now).is_err());
+}